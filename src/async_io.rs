@@ -1,72 +1,234 @@
-use std::ops::DerefMut;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
 
+use serde_json::Value;
 use tokio::io::AsyncRead;
 use tokio::stream::Stream;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum RpcResponseState {
-    NoNewLines,
-    OneNewLine,
-    TwoNewLines,
-}
+const READ_SIZE: usize = 4096;
 
-/// A byte stream that terminates when 2 consecutive newlines are received
-pub struct RpcResponseStream<SP: DerefMut<Target = AR>, AR: AsyncRead> {
-    pub inner: SP,
-    pub state: RpcResponseState,
-    pub buf: [u8; 4096],
+/// A stream of JSON values read from `inner`
+///
+/// Bytes are buffered across reads until they contain a complete value, and any bytes after it
+/// are kept for the next one, so values may be split across reads or several may arrive in one.
+/// Whitespace between values (such as lightningd's `\n\n` terminator) is skipped.
+///
+/// Until the stream ends, a value is only decoded once its `\n\n` terminator has arrived, so a
+/// large value spread over many reads is scanned once rather than re-parsed after every read.
+pub struct RpcResponseStream<R: AsyncRead> {
+    pub inner: R,
+    buf: Vec<u8>,
+    /// how much of `buf` has been searched for a terminator
+    scanned: usize,
+    eof: bool,
 }
-impl<SP, AR> RpcResponseStream<SP, AR>
-where
-    SP: DerefMut<Target = AR>,
-    AR: AsyncRead,
-{
-    pub fn new(sp: SP) -> Self {
+impl<R: AsyncRead> RpcResponseStream<R> {
+    pub fn new(inner: R) -> Self {
         RpcResponseStream {
-            inner: sp,
-            state: RpcResponseState::NoNewLines,
-            buf: [0; 4096],
+            inner,
+            buf: Vec::new(),
+            scanned: 0,
+            eof: false,
+        }
+    }
+
+    /// attempts to decode a value from the front of the buffer, removing it if successful
+    fn decode(&mut self) -> Option<Result<Value, tokio::io::Error>> {
+        loop {
+            let end = if self.eof {
+                self.buf.len()
+            } else {
+                // the terminator may be split across reads
+                let from = self.scanned.saturating_sub(1);
+                match self.buf[from..].windows(2).position(|w| w == b"\n\n") {
+                    Some(pos) => from + pos + 2,
+                    None => {
+                        self.scanned = self.buf.len();
+                        return None; // need more bytes
+                    }
+                }
+            };
+            self.scanned = end;
+            let mut values =
+                serde_json::Deserializer::from_slice(&self.buf[..end]).into_iter::<Value>();
+            match values.next() {
+                Some(Ok(value)) => {
+                    let len = values.byte_offset();
+                    self.buf.drain(..len);
+                    self.scanned -= len;
+                    return Some(Ok(value));
+                }
+                // a blank line within the value, look for the next terminator
+                Some(Err(e)) if e.is_eof() && !self.eof => (),
+                Some(Err(e)) if e.is_eof() => return None,
+                Some(Err(e)) => {
+                    // nothing after a syntax error can be trusted
+                    self.buf.clear();
+                    self.scanned = 0;
+                    return Some(Err(tokio::io::Error::new(
+                        tokio::io::ErrorKind::InvalidData,
+                        e,
+                    )));
+                }
+                None => {
+                    // only whitespace up to the terminator
+                    self.buf.drain(..end);
+                    self.scanned = 0;
+                    if self.eof {
+                        return None;
+                    }
+                }
+            }
         }
     }
 }
-impl<SP, AR> Stream for RpcResponseStream<SP, AR>
+impl<R> Stream for RpcResponseStream<R>
 where
-    SP: DerefMut<Target = AR> + std::marker::Unpin,
-    AR: AsyncRead + std::marker::Unpin,
+    R: AsyncRead + std::marker::Unpin,
 {
-    type Item = Result<Vec<u8>, tokio::io::Error>;
+    type Item = Result<Value, tokio::io::Error>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let mut buf = self.buf;
-        if self.state == RpcResponseState::TwoNewLines {
-            return Poll::Ready(None);
-        }
-        let inner_pin: Pin<&mut AR> = Pin::new(self.inner.deref_mut());
-        let bytes_read_poll = AsyncRead::poll_read(inner_pin, cx, &mut buf);
-        let state = &mut self.state;
-        let res = match bytes_read_poll {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(Ok(n)) => {
-                let mut buf = &buf[..n];
-                if buf.ends_with(b"\n\n") {
-                    *state = RpcResponseState::TwoNewLines;
-                    buf = &buf[..n - 2];
-                } else if *state == RpcResponseState::OneNewLine && buf.starts_with(b"\n") {
-                    buf = &buf[..n - 1];
-                } else if buf.ends_with(b"\n") {
-                    *state = RpcResponseState::OneNewLine;
-                    buf = &buf[..n - 1];
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(res) = this.decode() {
+                return Poll::Ready(Some(res));
+            }
+            if this.eof {
+                return Poll::Ready(if this.buf.is_empty() {
+                    None
                 } else {
-                    *state = RpcResponseState::NoNewLines;
-                }
-                Poll::Ready(Some(Ok(buf.to_owned())))
+                    this.buf.clear();
+                    this.scanned = 0;
+                    Some(Err(tokio::io::Error::new(
+                        tokio::io::ErrorKind::UnexpectedEof,
+                        "stream closed mid-value",
+                    )))
+                });
+            }
+            let len = this.buf.len();
+            this.buf.resize(len + READ_SIZE, 0);
+            let res = Pin::new(&mut this.inner).poll_read(cx, &mut this.buf[len..]);
+            let n = match &res {
+                Poll::Ready(Ok(n)) => *n,
+                _ => 0,
+            };
+            this.buf.truncate(len + n);
+            match res {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => this.eof = true,
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
             }
-            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
-        };
-        self.buf = buf;
-        res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use tokio::stream::StreamExt;
+
+    use super::*;
+
+    /// returns at most one chunk per read
+    struct ChunkedReader(VecDeque<Vec<u8>>);
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<tokio::io::Result<usize>> {
+            let chunk = match self.0.pop_front() {
+                Some(chunk) => chunk,
+                None => return Poll::Ready(Ok(0)),
+            };
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.0.push_front(chunk[n..].to_vec());
+            }
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    fn stream(chunks: &[&[u8]]) -> RpcResponseStream<ChunkedReader> {
+        RpcResponseStream::new(ChunkedReader(chunks.iter().map(|c| c.to_vec()).collect()))
+    }
+
+    #[tokio::test]
+    async fn split_frame() {
+        let mut s = stream(&[b"{\"id\":0,\"res", b"ult\":{\"a\":\"\\n\\n\"}}\n", b"\n"]);
+        assert_eq!(
+            s.next().await.unwrap().unwrap(),
+            serde_json::json!({"id": 0, "result": {"a": "\n\n"}})
+        );
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn split_terminator() {
+        let mut s = stream(&[b"{\"id\":0}\n", b"\n{\"id\"", b":1}\n\n"]);
+        assert_eq!(
+            s.next().await.unwrap().unwrap(),
+            serde_json::json!({"id": 0})
+        );
+        assert_eq!(
+            s.next().await.unwrap().unwrap(),
+            serde_json::json!({"id": 1})
+        );
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn coalesced_frames() {
+        let mut s = stream(&[b"{\"id\":0}\n\n{\"id\":1}\n\n{\"id\":", b"2}\n\n"]);
+        for id in 0..3 {
+            assert_eq!(
+                s.next().await.unwrap().unwrap(),
+                serde_json::json!({ "id": id })
+            );
+        }
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn large_frame() {
+        let value = serde_json::json!({ "result": "x".repeat(READ_SIZE * 3) });
+        let bytes = serde_json::to_vec(&value).unwrap();
+        let chunks: Vec<&[u8]> = bytes.chunks(1000).collect();
+        let mut s = stream(&chunks);
+        assert_eq!(s.next().await.unwrap().unwrap(), value);
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn blank_line_in_frame() {
+        let mut s = stream(&[b"{\"id\":\n", b"\n0}\n\n{\"id\":1}\n\n"]);
+        assert_eq!(
+            s.next().await.unwrap().unwrap(),
+            serde_json::json!({"id": 0})
+        );
+        assert_eq!(
+            s.next().await.unwrap().unwrap(),
+            serde_json::json!({"id": 1})
+        );
+        assert!(s.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn truncated_frame() {
+        let mut s = stream(&[b"{\"id\":0}\n\n{\"id\""]);
+        assert_eq!(
+            s.next().await.unwrap().unwrap(),
+            serde_json::json!({"id": 0})
+        );
+        assert_eq!(
+            s.next().await.unwrap().unwrap_err().kind(),
+            tokio::io::ErrorKind::UnexpectedEof
+        );
+        assert!(s.next().await.is_none());
     }
 }
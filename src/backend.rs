@@ -0,0 +1,272 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use c_lightning_pruning_plugin::plugin::{Builder, ConfigOption, OptionType, Responder};
+use c_lightning_pruning_plugin::rpc::{IntoRpcResult, RpcError, RpcParams};
+use failure::Error;
+use serde_json::Value;
+
+use crate::bitcoin::{make_bitcoin_req, BitcoinClient};
+use crate::refetch;
+use crate::status::StatusArc;
+
+/// methods lightningd calls on its Bitcoin backend
+const METHODS: &[&str] = &[
+    "getchaininfo",
+    "estimatefees",
+    "getrawblockbyheight",
+    "getutxout",
+    "sendrawtransaction",
+];
+
+/// how long to wait before asking peers for a pruned block again
+const BACKFILL_RETRY: Duration = Duration::from_secs(30);
+
+/// `estimatefees` targets, and the `estimatesmartfee` mode used for each, matching `bcli`
+const FEE_TARGETS: &[(u64, &str)] = &[
+    (2, "CONSERVATIVE"),
+    (6, "ECONOMICAL"),
+    (12, "ECONOMICAL"),
+    (100, "ECONOMICAL"),
+];
+
+/// bitcoind error code for a height past its tip
+const INVALID_PARAMETER: i64 = -8;
+
+/// A backend request from lightningd, answered from its own task
+#[derive(Clone, Debug)]
+pub struct BackendRequest {
+    pub responder: Responder,
+    pub method: &'static str,
+    pub params: RpcParams,
+}
+
+/// Registers the options replacing those `bcli` registers, and the backend methods, which are
+/// passed on to [`run`]
+pub fn register(
+    builder: Builder,
+    requests: tokio::sync::mpsc::UnboundedSender<BackendRequest>,
+) -> Builder {
+    let builder = builder
+        .option(ConfigOption::new(
+            "bitcoin-datadir",
+            OptionType::String,
+            "bitcoind's datadir, used to find its cookie file",
+        ))
+        .option(ConfigOption::new(
+            "bitcoin-rpcuser",
+            OptionType::String,
+            "bitcoind RPC username",
+        ))
+        .option(ConfigOption::new(
+            "bitcoin-rpcpassword",
+            OptionType::String,
+            "bitcoind RPC password",
+        ))
+        .option(ConfigOption::new(
+            "bitcoin-rpcconnect",
+            OptionType::String,
+            "bitcoind RPC host to connect to",
+        ))
+        .option(ConfigOption::new(
+            "bitcoin-rpcport",
+            OptionType::Int,
+            "bitcoind RPC port",
+        ));
+    METHODS.iter().fold(builder, |builder, method| {
+        let requests = requests.clone();
+        builder.deferred_rpcmethod(
+            *method,
+            "",
+            "Bitcoin backend method",
+            move |responder, params| {
+                requests
+                    .send(BackendRequest {
+                        responder,
+                        method,
+                        params: params.clone(),
+                    })
+                    .map_err(|e| format!("{}", e))
+                    .with_info(6, "backend unavailable")
+            },
+        )
+    })
+}
+
+fn bitcoind_code(e: &Error) -> Option<i64> {
+    e.downcast_ref::<RpcError>().and_then(|e| e.code.as_i64())
+}
+
+/// bitcoind reports amounts in BTC, lightningd expects satoshis
+fn btc_to_sat(btc: f64) -> u64 {
+    (btc * 100_000_000.0).round() as u64
+}
+
+fn param<'a>(params: &'a RpcParams, idx: usize, name: &str) -> Option<&'a Value> {
+    match params {
+        RpcParams::ByPosition(a) => a.get(idx),
+        RpcParams::ByName(a) => a.get(name),
+    }
+    .filter(|a| !a.is_null())
+}
+
+fn required<'a>(params: &'a RpcParams, idx: usize, name: &str) -> Result<&'a Value, Error> {
+    param(params, idx, name).ok_or_else(|| failure::format_err!("missing parameter {}", name))
+}
+
+async fn getchaininfo(bitcoin: &BitcoinClient) -> Result<Value, Error> {
+    #[derive(serde::Deserialize)]
+    struct ChainInfo {
+        chain: String,
+        headers: u64,
+        blocks: u64,
+        #[serde(default)]
+        initialblockdownload: bool,
+    }
+    let info: ChainInfo =
+        serde_json::from_value(make_bitcoin_req(bitcoin, "getblockchaininfo", Vec::new()).await?)?;
+    Ok(serde_json::json!({
+        "chain": info.chain,
+        "headercount": info.headers,
+        "blockcount": info.blocks,
+        "ibd": info.initialblockdownload,
+    }))
+}
+
+async fn estimatefees(bitcoin: &BitcoinClient) -> Result<Value, Error> {
+    let mempool = make_bitcoin_req(bitcoin, "getmempoolinfo", Vec::new()).await?;
+    let floor = ["mempoolminfee", "minrelaytxfee"]
+        .iter()
+        .filter_map(|k| mempool[*k].as_f64())
+        .fold(0.0, f64::max);
+    let mut feerates = Vec::new();
+    for (blocks, mode) in FEE_TARGETS {
+        let res = make_bitcoin_req(
+            bitcoin,
+            "estimatesmartfee",
+            vec![(*blocks).into(), (*mode).into()],
+        )
+        .await?;
+        // missing while bitcoind hasn't seen enough transactions to estimate
+        if let Some(feerate) = res["feerate"].as_f64() {
+            feerates.push(serde_json::json!({
+                "blocks": blocks,
+                "feerate": btc_to_sat(feerate.max(floor)),
+            }));
+        }
+    }
+    Ok(serde_json::json!({
+        "feerate_floor": btc_to_sat(floor),
+        "feerates": feerates,
+    }))
+}
+
+/// Returns the block's hex, fetching it from peers again for as long as it takes if it was pruned
+async fn getblock(bitcoin: &BitcoinClient, height: u64, hash: &Value) -> Result<Value, Error> {
+    loop {
+        match make_bitcoin_req(bitcoin, "getblock", vec![hash.clone(), 0.into()]).await {
+            Err(e) if refetch::is_pruned(&e) => (),
+            res => return res,
+        }
+        log::info!("block {} was pruned, fetching it from peers", height);
+        let res = match refetch::peers(bitcoin).await {
+            Ok(peers) => refetch::fetch_block(bitcoin, height, &peers).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            log::warn!(
+                "unable to fetch block {}, retrying in {}s: {}",
+                height,
+                BACKFILL_RETRY.as_secs(),
+                e
+            );
+            tokio::time::delay_for(BACKFILL_RETRY).await;
+        }
+    }
+}
+
+async fn getrawblockbyheight(bitcoin: &BitcoinClient, params: &RpcParams) -> Result<Value, Error> {
+    let height = required(params, 0, "height")?
+        .as_u64()
+        .ok_or_else(|| failure::format_err!("invalid height"))?;
+    let hash = match make_bitcoin_req(bitcoin, "getblockhash", vec![height.into()]).await {
+        Ok(hash) => hash,
+        // lightningd polls for the block after the tip
+        Err(e) if bitcoind_code(&e) == Some(INVALID_PARAMETER) => {
+            return Ok(serde_json::json!({ "blockhash": null, "block": null }));
+        }
+        Err(e) => return Err(e),
+    };
+    let block = getblock(bitcoin, height, &hash).await?;
+    Ok(serde_json::json!({ "blockhash": hash, "block": block }))
+}
+
+async fn getutxout(bitcoin: &BitcoinClient, params: &RpcParams) -> Result<Value, Error> {
+    let txid = required(params, 0, "txid")?.clone();
+    let vout = required(params, 1, "vout")?.clone();
+    let res = make_bitcoin_req(bitcoin, "gettxout", vec![txid, vout]).await?;
+    if res.is_null() {
+        // spent
+        return Ok(serde_json::json!({ "amount": null, "script": null }));
+    }
+    let amount = res["value"]
+        .as_f64()
+        .ok_or_else(|| failure::format_err!("gettxout returned no value"))?;
+    Ok(serde_json::json!({
+        "amount": btc_to_sat(amount),
+        "script": res["scriptPubKey"]["hex"],
+    }))
+}
+
+async fn sendrawtransaction(bitcoin: &BitcoinClient, params: &RpcParams) -> Result<Value, Error> {
+    let tx = required(params, 0, "tx")?.clone();
+    let allowhighfees = param(params, 1, "allowhighfees")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    let mut args = vec![tx];
+    if allowhighfees {
+        args.push(0.into()); // no maximum feerate
+    }
+    // lightningd expects rejections as a result, not an error
+    Ok(
+        match make_bitcoin_req(bitcoin, "sendrawtransaction", args).await {
+            Ok(_) => serde_json::json!({ "success": true, "errmsg": "" }),
+            Err(e) => serde_json::json!({ "success": false, "errmsg": format!("{}", e) }),
+        },
+    )
+}
+
+async fn handle(bitcoin: &BitcoinClient, method: &str, params: &RpcParams) -> Result<Value, Error> {
+    match method {
+        "getchaininfo" => getchaininfo(bitcoin).await,
+        "estimatefees" => estimatefees(bitcoin).await,
+        "getrawblockbyheight" => getrawblockbyheight(bitcoin, params).await,
+        "getutxout" => getutxout(bitcoin, params).await,
+        "sendrawtransaction" => sendrawtransaction(bitcoin, params).await,
+        _ => Err(failure::format_err!("unknown backend method {}", method)),
+    }
+}
+
+/// Answers backend requests as they arrive, each in its own task so a block being fetched from
+/// peers doesn't hold up the rest
+pub async fn run(
+    bitcoin: Arc<BitcoinClient>,
+    status: StatusArc,
+    mut requests: tokio::sync::mpsc::UnboundedReceiver<BackendRequest>,
+) {
+    while let Some(req) = requests.recv().await {
+        let bitcoin = bitcoin.clone();
+        let status = status.clone();
+        tokio::spawn(async move {
+            let res = handle(&bitcoin, req.method, &req.params)
+                .await
+                .map_err(|e| format!("{}", e))
+                .with_info(11, "bitcoind error");
+            if let Err(e) = &res {
+                log::error!("BACKEND REQUEST HANDLER ERROR: {}: {}", req.method, e);
+                status.count_error("backend");
+            }
+            req.responder.respond(res);
+        });
+    }
+}
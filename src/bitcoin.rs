@@ -0,0 +1,217 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+
+use c_lightning_pruning_plugin::rpc::{JsonRpcV2Id, RpcError, RpcParams, RpcReq};
+use failure::Error;
+use serde_json::Value;
+
+/// bitcoind replies in JSON-RPC 1.0 style, with both `result` and `error` present
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct BitcoinRes {
+    #[serde(default)]
+    pub result: Option<Value>,
+    #[serde(default)]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PruneMode {
+    /// `-prune=1`: blocks are only pruned by `pruneblockchain`
+    Manual,
+    /// `-prune=<MiB>`: bitcoind prunes on its own to stay under its target size
+    Automatic,
+    /// `-prune=0`: `pruneblockchain` is rejected
+    Disabled,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct BlockchainInfo {
+    pub blocks: u64,
+    #[serde(default)]
+    pub initialblockdownload: bool,
+    #[serde(default)]
+    pub verificationprogress: Option<f64>,
+    #[serde(default)]
+    pub pruned: bool,
+    #[serde(default)]
+    pub pruneheight: Option<u64>,
+    #[serde(default)]
+    pub size_on_disk: Option<u64>,
+    #[serde(default)]
+    pub automatic_pruning: Option<bool>,
+    #[serde(default)]
+    pub prune_target_size: Option<u64>,
+}
+impl BlockchainInfo {
+    /// Estimates the height bitcoind has to prune to for its block files to fit in
+    /// `target_size` bytes, assuming the stored blocks are of equal size.
+    /// Returns `None` if they already fit.
+    pub fn target_height(&self, target_size: u64) -> Option<u64> {
+        let size = self.size_on_disk?;
+        if size <= target_size {
+            return None;
+        }
+        let pruneheight = self.pruneheight.unwrap_or_default();
+        let stored_blocks = self.blocks.saturating_sub(pruneheight).max(1);
+        let block_size = (size / stored_blocks).max(1);
+        let excess_blocks = (size - target_size).div_ceil(block_size);
+        Some(pruneheight + excess_blocks)
+    }
+    pub fn prune_mode(&self) -> PruneMode {
+        match (self.pruned, self.automatic_pruning) {
+            (false, _) => PruneMode::Disabled,
+            (true, Some(true)) => PruneMode::Automatic,
+            (true, _) => PruneMode::Manual,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum BitcoinAuth {
+    UserPass(String, String),
+    /// the `.cookie` file written by bitcoind on startup when no rpcpassword is configured
+    Cookie(PathBuf),
+}
+
+fn read_cookie(path: &Path) -> Result<(String, String), Error> {
+    let cookie = std::fs::read_to_string(path)
+        .map_err(|e| failure::format_err!("{}: {}", path.display(), e))?;
+    let mut split = cookie.trim().splitn(2, ':');
+    match (split.next(), split.next()) {
+        (Some(user), Some(password)) => Ok((user.to_owned(), password.to_owned())),
+        _ => Err(failure::format_err!("{}: malformed cookie", path.display())),
+    }
+}
+
+/// An http client for bitcoind's JSON-RPC interface
+#[derive(Debug)]
+pub struct BitcoinClient {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    auth: BitcoinAuth,
+    /// `None` until the cookie file has been read
+    credentials: RwLock<Option<(String, String)>>,
+}
+impl BitcoinClient {
+    /// bitcoind may not have written its cookie yet, so it is only read on the first request
+    pub fn new(client: reqwest::Client, url: reqwest::Url, auth: BitcoinAuth) -> Self {
+        let credentials = match &auth {
+            BitcoinAuth::UserPass(user, password) => Some((user.clone(), password.clone())),
+            BitcoinAuth::Cookie(_) => None,
+        };
+        BitcoinClient {
+            client,
+            url,
+            auth,
+            credentials: RwLock::new(credentials),
+        }
+    }
+
+    /// re-reads the cookie file, returning whether the credentials changed
+    fn reload_cookie(&self) -> Result<bool, Error> {
+        if let BitcoinAuth::Cookie(path) = &self.auth {
+            let credentials = read_cookie(path)?;
+            let mut guard = self
+                .credentials
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            if guard.as_ref() != Some(&credentials) {
+                log::info!("read bitcoind cookie from {}", path.display());
+                *guard = Some(credentials);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn credentials(&self) -> Result<(String, String), Error> {
+        let cached = self
+            .credentials
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        match cached {
+            Some(credentials) => Ok(credentials),
+            None => {
+                self.reload_cookie()?;
+                self.credentials()
+            }
+        }
+    }
+
+    async fn send(&self, req: &RpcReq) -> Result<reqwest::Response, Error> {
+        let (user, password) = self.credentials()?;
+        Ok(self
+            .client
+            .post(self.url.clone())
+            .basic_auth(user, Some(password))
+            .json(req)
+            .send()
+            .await?)
+    }
+}
+
+pub async fn make_bitcoin_req(
+    bitcoin: &BitcoinClient,
+    method: &'static str,
+    params: Vec<Value>,
+) -> Result<Value, Error> {
+    let req = RpcReq {
+        id: Some(JsonRpcV2Id::Num(0.into())),
+        jsonrpc: Default::default(),
+        method: Cow::Borrowed(method),
+        params: RpcParams::ByPosition(params),
+    };
+    let mut res = bitcoin.send(&req).await?;
+    // bitcoind writes a new cookie every time it restarts
+    if res.status() == reqwest::StatusCode::UNAUTHORIZED && bitcoin.reload_cookie()? {
+        res = bitcoin.send(&req).await?;
+    }
+    let status = res.status();
+    let body = res.text().await?;
+    match serde_json::from_str(&body) {
+        Ok(BitcoinRes { error: Some(e), .. }) => Err(e.into()),
+        Ok(BitcoinRes { result, .. }) if status.is_success() => Ok(result.unwrap_or(Value::Null)),
+        _ => Err(failure::format_err!("{}: {:?}", status, body)),
+    }
+}
+
+pub async fn get_blockchain_info(bitcoin: &BitcoinClient) -> Result<BlockchainInfo, Error> {
+    Ok(serde_json::from_value(
+        make_bitcoin_req(bitcoin, "getblockchaininfo", Vec::new()).await?,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(blocks: u64, pruneheight: u64, size_on_disk: u64) -> BlockchainInfo {
+        BlockchainInfo {
+            blocks,
+            initialblockdownload: false,
+            verificationprogress: Some(1.0),
+            pruned: true,
+            pruneheight: Some(pruneheight),
+            size_on_disk: Some(size_on_disk),
+            automatic_pruning: Some(false),
+            prune_target_size: None,
+        }
+    }
+
+    #[test]
+    fn target_height() {
+        // already under target
+        assert_eq!(info(1000, 0, 500).target_height(1000), None);
+        assert_eq!(info(1000, 0, 1000).target_height(1000), None);
+        // 800 stored blocks of 10 bytes, 300 of which have to go
+        assert_eq!(info(1000, 200, 8000).target_height(5000), Some(500));
+        // rounds up to a whole block
+        assert_eq!(info(1000, 200, 8000).target_height(4995), Some(501));
+        // pruneheight at or past the tip counts as a single stored block
+        assert_eq!(info(100, 150, 1000).target_height(500), Some(151));
+        assert_eq!(info(150, 150, 1000).target_height(500), Some(151));
+    }
+}
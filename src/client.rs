@@ -0,0 +1,387 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use c_lightning_pruning_plugin::async_io::RpcResponseStream;
+use c_lightning_pruning_plugin::rpc::{JsonRpcV2Id, RpcError, RpcParams, RpcReq, RpcRes};
+use failure::Error;
+use serde_json::Value;
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::UnixStream;
+use tokio::stream::StreamExt;
+use tokio::sync::oneshot;
+
+use crate::status::{unix_now, StatusArc};
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<RpcRes>>>>;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+#[serde(tag = "state")]
+pub enum ConnectionState {
+    Connected,
+    Disconnected {
+        /// unix timestamp of when the connection was lost
+        since: u64,
+        /// failed reconnection attempts so far
+        attempts: u64,
+        error: String,
+    },
+}
+
+/// A single connection to lightningd's RPC socket
+#[derive(Debug)]
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<UnixStream>>,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+}
+impl Connection {
+    fn new(stream: UnixStream) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let pending = Pending::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        tokio::spawn(read_responses(
+            RpcResponseStream::new(reader),
+            pending.clone(),
+            closed.clone(),
+        ));
+        Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            closed,
+        }
+    }
+
+    async fn call(&self, id: u64, req: &[u8]) -> Result<RpcRes, tokio::io::Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, sender);
+        let res = if self.closed.load(Ordering::SeqCst) {
+            Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::NotConnected,
+                "socket closed",
+            ))
+        } else {
+            self.writer.lock().await.write_all(req).await
+        };
+        if let Err(e) = res {
+            self.closed.store(true, Ordering::SeqCst);
+            self.pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&id);
+            return Err(e);
+        }
+        receiver.await.map_err(|_| {
+            tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "socket closed")
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ClientState {
+    connection: Option<Arc<Connection>>,
+    state: ConnectionState,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+impl ClientState {
+    /// drops the connection if the socket has closed
+    fn check_closed(&mut self, status: Option<&StatusArc>) {
+        if let Some(connection) = &self.connection {
+            if connection.closed.load(Ordering::SeqCst) {
+                log::warn!("lost connection to lightningd RPC socket");
+                self.connection = None;
+                self.state = ConnectionState::Disconnected {
+                    since: unix_now(),
+                    attempts: 0,
+                    error: "socket closed".to_owned(),
+                };
+                self.publish(status);
+            }
+        }
+    }
+
+    /// reports the connection state in `pruning-status`
+    fn publish(&self, status: Option<&StatusArc>) {
+        if let Some(status) = status {
+            let state = self.state.clone();
+            status.update(|s| s.lightningd_connection = Some(state));
+        }
+    }
+}
+
+/// A client for lightningd's RPC socket that can be shared between tasks
+///
+/// Every request gets a unique id, and a reader task hands each response to the caller waiting
+/// on its id, so any number of requests can be in flight on the one connection. If the socket
+/// closes, the next request reconnects, backing off exponentially while lightningd is unreachable.
+#[derive(Clone, Debug)]
+pub struct LightningClient {
+    /// where to reconnect to, if anywhere
+    path: Option<PathBuf>,
+    /// where to report the connection state, if anywhere
+    status: Option<StatusArc>,
+    next_id: Arc<AtomicU64>,
+    state: Arc<tokio::sync::Mutex<ClientState>>,
+}
+impl LightningClient {
+    pub async fn connect(path: &Path) -> Result<Self, Error> {
+        let mut client = Self::new(UnixStream::connect(path).await?);
+        client.path = Some(path.to_owned());
+        Ok(client)
+    }
+
+    pub fn new(stream: UnixStream) -> Self {
+        LightningClient {
+            path: None,
+            status: None,
+            next_id: Arc::new(AtomicU64::new(0)),
+            state: Arc::new(tokio::sync::Mutex::new(ClientState {
+                connection: Some(Arc::new(Connection::new(stream))),
+                state: ConnectionState::Connected,
+                backoff: INITIAL_BACKOFF,
+                retry_at: None,
+            })),
+        }
+    }
+
+    /// Reports the connection state in `status` from now on, as it changes
+    pub fn with_status(mut self, status: StatusArc) -> Self {
+        status.update(|s| s.lightningd_connection = Some(ConnectionState::Connected));
+        self.status = Some(status);
+        self
+    }
+
+    /// returns the current connection, reconnecting if it has closed and the backoff has elapsed
+    async fn connection(&self) -> Result<Arc<Connection>, Error> {
+        let mut state = self.state.lock().await;
+        state.check_closed(self.status.as_ref());
+        if let Some(connection) = &state.connection {
+            return Ok(connection.clone());
+        }
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| failure::format_err!("lightningd RPC socket closed"))?;
+        if let Some(retry_at) = state.retry_at {
+            let now = Instant::now();
+            if now < retry_at {
+                return Err(failure::format_err!(
+                    "lightningd RPC socket disconnected, reconnecting in {}s",
+                    (retry_at - now).as_secs() + 1
+                ));
+            }
+        }
+        match UnixStream::connect(path).await {
+            Ok(stream) => {
+                log::info!("reconnected to lightningd RPC socket");
+                let connection = Arc::new(Connection::new(stream));
+                state.connection = Some(connection.clone());
+                state.state = ConnectionState::Connected;
+                state.backoff = INITIAL_BACKOFF;
+                state.retry_at = None;
+                state.publish(self.status.as_ref());
+                Ok(connection)
+            }
+            Err(e) => {
+                let backoff = state.backoff;
+                log::warn!(
+                    "unable to reconnect to lightningd RPC socket, retrying in {}s: {}",
+                    backoff.as_secs(),
+                    e
+                );
+                state.retry_at = Some(Instant::now() + backoff);
+                state.backoff = (backoff * 2).min(MAX_BACKOFF);
+                if let ConnectionState::Disconnected {
+                    attempts, error, ..
+                } = &mut state.state
+                {
+                    *attempts += 1;
+                    *error = format!("{}", e);
+                }
+                state.publish(self.status.as_ref());
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn call(
+        &self,
+        method: &'static str,
+        params: RpcParams,
+    ) -> Result<Result<Value, RpcError>, Error> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut req = serde_json::to_vec(&RpcReq {
+            id: Some(JsonRpcV2Id::Num(id.into())),
+            jsonrpc: Default::default(),
+            method: Cow::Borrowed(method),
+            params,
+        })?;
+        // terminated the way lightningd terminates its responses
+        req.extend_from_slice(b"\n\n");
+        let res = self.connection().await?.call(id, &req).await;
+        if res.is_err() {
+            // report the disconnect now rather than on the next request
+            self.state.lock().await.check_closed(self.status.as_ref());
+        }
+        let res = res?;
+
+        Ok(res.result.res())
+    }
+}
+
+/// dispatches responses to their callers until the socket closes
+async fn read_responses(
+    mut stream: RpcResponseStream<ReadHalf<UnixStream>>,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+) {
+    while let Some(res) = stream.next().await {
+        let res = match res
+            .map_err(Error::from)
+            .and_then(|res| serde_json::from_value::<RpcRes>(res).map_err(Error::from))
+        {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!("RPC SOCKET ERROR: {}", e);
+                break;
+            }
+        };
+        let id = match &res.id {
+            JsonRpcV2Id::Num(n) => n.as_u64(),
+            _ => None,
+        };
+        let sender = id.and_then(|id| {
+            pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&id)
+        });
+        match sender {
+            Some(sender) => sender
+                .send(res)
+                .unwrap_or_else(|_| log::warn!("caller of request {:?} has gone away", id)),
+            None => log::warn!("unexpected response for request {:?}", res.id),
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+    // dropping the senders wakes any callers still waiting
+    pending
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn responses_out_of_order() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let client = LightningClient::new(client);
+        let (server_reader, mut server_writer) = tokio::io::split(server);
+        let server = tokio::spawn(async move {
+            let mut reqs = RpcResponseStream::new(server_reader);
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let req: RpcReq =
+                    serde_json::from_value(reqs.next().await.unwrap().unwrap()).unwrap();
+                ids.push((req.id.unwrap(), req.method));
+            }
+            // answer the second request first
+            for (id, method) in ids.into_iter().rev() {
+                let res = serde_json::to_vec(&RpcRes {
+                    id,
+                    jsonrpc: Default::default(),
+                    result: Ok(Value::String(method.into_owned())).into(),
+                })
+                .unwrap();
+                server_writer.write_all(&res).await.unwrap();
+                server_writer.write_all(b"\n\n").await.unwrap();
+            }
+        });
+        let (a, b) = futures::join!(
+            client.call("getinfo", RpcParams::ByPosition(Vec::new())),
+            client.call("listfunds", RpcParams::ByPosition(Vec::new())),
+        );
+        assert_eq!(a.unwrap().unwrap(), Value::String("getinfo".to_owned()));
+        assert_eq!(b.unwrap().unwrap(), Value::String("listfunds".to_owned()));
+        server.await.unwrap();
+        // the server has hung up
+        assert!(client
+            .call("getinfo", RpcParams::ByPosition(Vec::new()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let path = std::env::temp_dir().join(format!("pruning-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let status = StatusArc::default();
+        let client = LightningClient::connect(&path)
+            .await
+            .unwrap()
+            .with_status(status.clone());
+        // hang up on the first connection
+        drop(listener.accept().await.unwrap());
+        assert!(client
+            .call("getinfo", RpcParams::ByPosition(Vec::new()))
+            .await
+            .is_err());
+        match status.get().lightningd_connection {
+            Some(ConnectionState::Disconnected { .. }) => (),
+            state => panic!("unexpected state {:?}", state),
+        }
+        // the first reconnection attempt is immediate
+        let (done, wait_done) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let req: RpcReq = serde_json::from_value(
+                RpcResponseStream::new(reader)
+                    .next()
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            )
+            .unwrap();
+            let res = serde_json::to_vec(&RpcRes {
+                id: req.id.unwrap(),
+                jsonrpc: Default::default(),
+                result: Ok(Value::Null).into(),
+            })
+            .unwrap();
+            writer.write_all(&res).await.unwrap();
+            writer.write_all(b"\n\n").await.unwrap();
+            wait_done.await.unwrap();
+        });
+        assert_eq!(
+            client
+                .call("getinfo", RpcParams::ByPosition(Vec::new()))
+                .await
+                .unwrap()
+                .unwrap(),
+            Value::Null
+        );
+        match status.get().lightningd_connection {
+            Some(ConnectionState::Connected) => (),
+            state => panic!("unexpected state {:?}", state),
+        }
+        done.send(()).unwrap();
+        server.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use c_lightning_pruning_plugin::rpc::RpcParams;
+use failure::Error;
+use serde_json::Value;
+
+use crate::client::LightningClient;
+
+/// every key the plugin stores lives under this one
+const PREFIX: &str = "pruning";
+
+/// `deldatastore` error code for a key that doesn't exist
+const DOES_NOT_EXIST: u64 = 1200;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct DatastoreEntry {
+    pub key: Vec<String>,
+    #[serde(default)]
+    pub string: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+struct DatastoreList {
+    #[serde(default)]
+    datastore: Vec<DatastoreEntry>,
+}
+
+fn params(key: &[&str]) -> serde_json::Map<String, Value> {
+    let mut params = serde_json::Map::new();
+    params.insert(
+        "key".to_owned(),
+        std::iter::once(PREFIX).chain(key.iter().copied()).collect(),
+    );
+    params
+}
+
+/// Returns the entries at and directly below `key`
+pub async fn list(client: &LightningClient, key: &[&str]) -> Result<Vec<DatastoreEntry>, Error> {
+    let res = client
+        .call("listdatastore", RpcParams::ByName(params(key)))
+        .await??;
+    Ok(serde_json::from_value::<DatastoreList>(res)?.datastore)
+}
+
+pub async fn get(client: &LightningClient, key: &[&str]) -> Result<Option<String>, Error> {
+    let len = key.len() + 1;
+    Ok(list(client, key)
+        .await?
+        .into_iter()
+        .find(|e| e.key.len() == len)
+        .and_then(|e| e.string))
+}
+
+pub async fn set(client: &LightningClient, key: &[&str], value: &str) -> Result<(), Error> {
+    let mut params = params(key);
+    params.insert("string".to_owned(), value.into());
+    params.insert("mode".to_owned(), "create-or-replace".into());
+    client
+        .call("datastore", RpcParams::ByName(params))
+        .await??;
+    Ok(())
+}
+
+/// Removes `key`, succeeding if it was already gone
+pub async fn del(client: &LightningClient, key: &[&str]) -> Result<(), Error> {
+    match client
+        .call("deldatastore", RpcParams::ByName(params(key)))
+        .await?
+    {
+        Err(e) if e.code.as_u64() != Some(DOES_NOT_EXIST) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,677 @@
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use c_lightning_pruning_plugin::plugin::{
+    Builder, ConfigOption, Configuration, Init, OptionType, Responder,
+};
+use c_lightning_pruning_plugin::rpc::*;
+use crossbeam_channel::Sender;
+use serde_json::Value;
+
+use crate::backend::{self, BackendRequest};
+use crate::init_info::{BitcoinInfo, InitInfo, Network};
+use crate::pruning::PruningCommand;
+use crate::status::StatusArc;
+
+/// number of `pruning-history` entries returned when no limit is given
+const DEFAULT_HISTORY_PAGE: usize = 100;
+/// longest accepted `pruning-interval`, a year, keeping the timer arithmetic far from overflow
+const MAX_INTERVAL: u64 = 365 * 24 * 60 * 60;
+/// longest accepted `pruning-pause` duration, longer pauses can be left without one
+const MAX_PAUSE: u64 = 365 * 24 * 60 * 60;
+
+/// State shared between the RPC handler thread and the pruning loop
+#[derive(Clone, Debug)]
+pub struct RpcContext {
+    pub init_sender: Sender<InitInfo>,
+    pub status: StatusArc,
+    pub commands: tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    /// notified when lightningd asks the plugin to exit
+    pub shutdown: Arc<tokio::sync::Notify>,
+    pub backend: tokio::sync::mpsc::UnboundedSender<BackendRequest>,
+}
+
+/// Answers a request with the outcome of the command it sent to the pruning loop, so the stdin
+/// thread doesn't wait on the loop and can go on reading lightningd's messages
+#[derive(Debug)]
+pub struct Reply<T> {
+    responder: Responder,
+    /// returned if the command fails
+    code: i64,
+    message: &'static str,
+    result: PhantomData<fn(T)>,
+}
+impl<T: serde::Serialize> Reply<T> {
+    pub fn new(responder: Responder, code: i64, message: &'static str) -> Self {
+        Reply {
+            responder,
+            code,
+            message,
+            result: PhantomData,
+        }
+    }
+
+    pub fn send(self, res: Result<T, String>) {
+        let res = res.with_info(self.code, self.message).and_then(|a| {
+            serde_json::to_value(a)
+                .map_err(|e| format!("{}", e))
+                .with_info(2, "serialization error")
+        });
+        self.responder.respond(res);
+    }
+}
+
+/// Registers the plugin's options, methods and subscriptions with their handlers
+pub fn builder(ctx: RpcContext) -> Builder {
+    let RpcContext {
+        init_sender,
+        status,
+        commands,
+        shutdown,
+        backend,
+    } = ctx;
+    // most methods pass a command on to the pruning loop, which answers it
+    let command = |handler: fn(
+        &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+        Responder,
+        &RpcParams,
+    ) -> Result<(), RpcError>| {
+        let commands = commands.clone();
+        move |responder, params: &RpcParams| handler(&commands, responder, params)
+    };
+    let builder = Builder::new()
+        .option(
+            ConfigOption::new(
+                "pruning-interval",
+                OptionType::Int,
+                "number of seconds to wait between pruning checks",
+            )
+            .with_default(600)
+            .dynamic(),
+        )
+        .option(
+            ConfigOption::new(
+                "pruning-dry-run",
+                OptionType::Bool,
+                "compute the prune height but never call pruneblockchain",
+            )
+            .with_default(false)
+            .dynamic(),
+        )
+        .option(
+            ConfigOption::new(
+                "pruning-block-batch",
+                OptionType::Int,
+                "number of new blocks to wait for between pruning checks, 0 to only use pruning-interval",
+            )
+            .with_default(1)
+            .dynamic(),
+        )
+        .option(
+            ConfigOption::new(
+                "pruning-min-verification-progress",
+                OptionType::String,
+                "defer pruning while bitcoind's verificationprogress is below this",
+            )
+            .with_default("0.9999")
+            .dynamic(),
+        )
+        .option(
+            ConfigOption::new(
+                "pruning-max-sync-gap",
+                OptionType::Int,
+                "defer pruning while lightningd and bitcoind block heights differ by more than this",
+            )
+            .with_default(6)
+            .dynamic(),
+        )
+        .option(
+            ConfigOption::new(
+                "pruning-anomaly-gap",
+                OptionType::Int,
+                "report an anomaly when lightningd and bitcoind block heights differ by more than this",
+            )
+            .with_default(144)
+            .dynamic(),
+        )
+        .option(
+            ConfigOption::new(
+                "pruning-target-mb",
+                OptionType::Int,
+                "only prune as far as needed to keep bitcoind's block files under this many MiB, 0 to always prune as far as is safe",
+            )
+            .with_default(0)
+            .dynamic(),
+        )
+        .option(
+            ConfigOption::new(
+                "pruning-history-limit",
+                OptionType::Int,
+                "number of pruneblockchain calls to keep in lightningd's datastore, 0 to stop recording them",
+            )
+            .with_default(1000)
+            .dynamic(),
+        )
+        .option(ConfigOption::new(
+            "pruning-metrics-bind",
+            OptionType::String,
+            "address to serve Prometheus metrics on, e.g. 127.0.0.1:9750",
+        ))
+        .option(ConfigOption::new(
+            "pruning-metrics-textfile",
+            OptionType::String,
+            "file to keep Prometheus metrics in for node_exporter's textfile collector",
+        ))
+        .rpcmethod(
+            "pruning-status",
+            "",
+            "Show the state of the pruning plugin",
+            move |_| handle_status(&status),
+        )
+        .deferred_rpcmethod(
+            "pruning-now",
+            "[dry_run]",
+            "Run a pruning check immediately",
+            command(handle_prune_now),
+        )
+        .deferred_rpcmethod(
+            "pruning-history",
+            "[limit] [offset]",
+            "List past pruneblockchain calls, newest first",
+            command(handle_history),
+        )
+        .deferred_rpcmethod(
+            "pruning-refetch",
+            "from to",
+            "Download pruned blocks from from to to again using getblockfrompeer",
+            command(handle_refetch),
+        )
+        .deferred_rpcmethod(
+            "pruning-pause",
+            "[duration]",
+            "Hold off pruning until pruning-resume is called, or for duration (e.g. 3600, 30m, 2h, 1d)",
+            command(handle_pause),
+        )
+        .deferred_rpcmethod(
+            "pruning-resume",
+            "",
+            "Resume pruning after pruning-pause",
+            command(handle_resume),
+        )
+        .setconfig({
+            let commands = commands.clone();
+            move |params| handle_setconfig(&commands, params)
+        })
+        .subscribe("block_added", {
+            let commands = commands.clone();
+            move |params| handle_block_added(&commands, params)
+        })
+        .subscribe("shutdown", move |_| {
+            log::info!("shutdown requested by lightningd");
+            shutdown.notify();
+            Ok(())
+        })
+        .on_init(move |init| handle_init(&init_sender, init));
+    if cfg!(feature = "backend") {
+        // lightningd can't run without its backend
+        backend::register(builder, backend).dynamic(false)
+    } else {
+        builder
+    }
+}
+
+pub fn handle_init(sender: &Sender<InitInfo>, init: Init) -> Result<(), RpcError> {
+    let options: LightningOptions = init.options()?;
+    options.validate().with_info(8, "invalid config value")?;
+    sender
+        .send(init_info(options, init.configuration))
+        .unwrap_or_else(|e| log::warn!("SEND ERROR: {}", e)); // ignore send error: means the reciever has already received and been dropped
+    Ok(())
+}
+
+pub fn handle_status(status: &StatusArc) -> Result<Value, RpcError> {
+    serde_json::to_value(status.get())
+        .map_err(|e| format!("{}", e))
+        .with_info(2, "serialization error")
+}
+
+pub fn handle_prune_now(
+    commands: &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    responder: Responder,
+    params: &RpcParams,
+) -> Result<(), RpcError> {
+    let dry_run = match params {
+        RpcParams::ByPosition(a) => a.first(),
+        RpcParams::ByName(a) => a.get("dry_run"),
+    }
+    .filter(|a| !a.is_null())
+    .map(|a| serde_json::from_value(a.clone()))
+    .transpose()
+    .map_err(|e| format!("{}", e))
+    .with_info(5, "params deserialization error")?;
+    commands
+        .send(PruningCommand::PruneNow {
+            dry_run,
+            reply: Reply::new(responder, 7, "pruning failed"),
+        })
+        .map_err(|e| format!("{}", e))
+        .with_info(6, "pruning loop unavailable")
+}
+
+pub fn handle_history(
+    commands: &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    responder: Responder,
+    params: &RpcParams,
+) -> Result<(), RpcError> {
+    #[derive(serde::Deserialize)]
+    struct HistoryParams {
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        offset: Option<usize>,
+    }
+    let params = match params {
+        RpcParams::ByName(a) => serde_json::Value::Object(a.clone()),
+        RpcParams::ByPosition(a) => serde_json::json!({
+            "limit": a.first(),
+            "offset": a.get(1),
+        }),
+    };
+    let params: HistoryParams = serde_json::from_value(params)
+        .map_err(|e| format!("{}", e))
+        .with_info(5, "params deserialization error")?;
+    commands
+        .send(PruningCommand::History {
+            offset: params.offset.unwrap_or(0),
+            limit: params.limit.unwrap_or(DEFAULT_HISTORY_PAGE),
+            reply: Reply::new(responder, 9, "datastore error"),
+        })
+        .map_err(|e| format!("{}", e))
+        .with_info(6, "pruning loop unavailable")
+}
+
+pub fn handle_refetch(
+    commands: &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    responder: Responder,
+    params: &RpcParams,
+) -> Result<(), RpcError> {
+    #[derive(serde::Deserialize)]
+    struct RefetchParams {
+        from: u64,
+        to: u64,
+    }
+    let params = match params {
+        RpcParams::ByName(a) => serde_json::Value::Object(a.clone()),
+        RpcParams::ByPosition(a) => serde_json::json!({
+            "from": a.first(),
+            "to": a.get(1),
+        }),
+    };
+    let params: RefetchParams = serde_json::from_value(params)
+        .map_err(|e| format!("{}", e))
+        .with_info(5, "params deserialization error")?;
+    commands
+        .send(PruningCommand::Refetch {
+            from: params.from,
+            to: params.to,
+            reply: Reply::new(responder, 10, "unable to refetch"),
+        })
+        .map_err(|e| format!("{}", e))
+        .with_info(6, "pruning loop unavailable")
+}
+
+/// Parses a number of seconds, optionally suffixed with `s`, `m`, `h` or `d`
+fn parse_duration(val: &Value) -> Result<u64, String> {
+    let secs = match val {
+        Value::Number(n) => n
+            .as_u64()
+            .ok_or_else(|| format!("invalid duration {}", n))?,
+        Value::String(s) => {
+            let s = s.trim();
+            let (num, unit) = match s.char_indices().last() {
+                Some((i, 's')) => (&s[..i], 1),
+                Some((i, 'm')) => (&s[..i], 60),
+                Some((i, 'h')) => (&s[..i], 60 * 60),
+                Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+                _ => (s, 1),
+            };
+            num.parse::<u64>()
+                .ok()
+                .and_then(|n| n.checked_mul(unit))
+                .ok_or_else(|| format!("invalid duration {:?}", s))?
+        }
+        _ => return Err(format!("invalid duration {}", val)),
+    };
+    if secs > MAX_PAUSE {
+        return Err(format!(
+            "duration must be at most {}s, pause without one to pause indefinitely",
+            MAX_PAUSE
+        ));
+    }
+    Ok(secs)
+}
+
+pub fn handle_pause(
+    commands: &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    responder: Responder,
+    params: &RpcParams,
+) -> Result<(), RpcError> {
+    let duration = match params {
+        RpcParams::ByPosition(a) => a.first(),
+        RpcParams::ByName(a) => a.get("duration"),
+    }
+    .filter(|a| !a.is_null())
+    .map(parse_duration)
+    .transpose()
+    .with_info(5, "params deserialization error")?;
+    commands
+        .send(PruningCommand::Pause {
+            duration,
+            reply: Reply::new(responder, 9, "datastore error"),
+        })
+        .map_err(|e| format!("{}", e))
+        .with_info(6, "pruning loop unavailable")
+}
+
+pub fn handle_resume(
+    commands: &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    responder: Responder,
+    _params: &RpcParams,
+) -> Result<(), RpcError> {
+    commands
+        .send(PruningCommand::Resume {
+            reply: Reply::new(responder, 9, "datastore error"),
+        })
+        .map_err(|e| format!("{}", e))
+        .with_info(6, "pruning loop unavailable")
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct BlockAdded {
+    pub height: u64,
+}
+
+pub fn handle_block_added(
+    commands: &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    params: &RpcParams,
+) -> Result<(), String> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum BlockAddedParams {
+        Current { block_added: BlockAdded },
+        Legacy { block: BlockAdded }, // before v22.11
+    }
+    let params = match params {
+        RpcParams::ByName(a) => serde_json::Value::Object(a.clone()),
+        RpcParams::ByPosition(a) => a.first().cloned().unwrap_or_default(),
+    };
+    let block = match serde_json::from_value(params).map_err(|e| format!("{}", e))? {
+        BlockAddedParams::Current { block_added } => block_added,
+        BlockAddedParams::Legacy { block } => block,
+    };
+    commands
+        .send(PruningCommand::BlockAdded(block.height))
+        .map_err(|e| format!("{}", e))
+}
+
+/// A new value for one of the plugin's options, as sent by lightningd's `setconfig`
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(tag = "config", content = "val")]
+pub enum ConfigUpdate {
+    #[serde(rename = "pruning-interval", deserialize_with = "deser_str_num")]
+    Interval(u64),
+    #[serde(rename = "pruning-dry-run", deserialize_with = "deser_str_bool")]
+    DryRun(bool),
+    #[serde(rename = "pruning-block-batch", deserialize_with = "deser_str_num")]
+    BlockBatch(u64),
+    #[serde(
+        rename = "pruning-min-verification-progress",
+        deserialize_with = "deser_str_float"
+    )]
+    MinVerificationProgress(f64),
+    #[serde(rename = "pruning-max-sync-gap", deserialize_with = "deser_str_num")]
+    MaxSyncGap(u64),
+    #[serde(rename = "pruning-anomaly-gap", deserialize_with = "deser_str_num")]
+    AnomalyGap(u64),
+    #[serde(rename = "pruning-target-mb", deserialize_with = "deser_str_num")]
+    TargetMb(u64),
+    #[serde(rename = "pruning-history-limit", deserialize_with = "deser_str_num")]
+    HistoryLimit(u64),
+}
+impl ConfigUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ConfigUpdate::Interval(0) => Err("pruning-interval must be at least 1".to_owned()),
+            ConfigUpdate::Interval(secs) if *secs > MAX_INTERVAL => Err(format!(
+                "pruning-interval must be at most {}, got {}",
+                MAX_INTERVAL, secs
+            )),
+            ConfigUpdate::MinVerificationProgress(p) if !(0.0..=1.0).contains(p) => Err(format!(
+                "pruning-min-verification-progress must be between 0 and 1, got {}",
+                p
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn handle_setconfig(
+    commands: &tokio::sync::mpsc::UnboundedSender<PruningCommand>,
+    params: &RpcParams,
+) -> Result<Value, RpcError> {
+    let params = match params {
+        RpcParams::ByName(a) => serde_json::Value::Object(a.clone()),
+        RpcParams::ByPosition(a) => serde_json::json!({
+            "config": a.first(),
+            "val": a.get(1),
+        }),
+    };
+    let update: ConfigUpdate = serde_json::from_value(params)
+        .map_err(|e| format!("{}", e))
+        .with_info(8, "invalid config value")?;
+    update.validate().with_info(8, "invalid config value")?;
+    commands
+        .send(PruningCommand::SetConfig(update))
+        .map_err(|e| format!("{}", e))
+        .with_info(6, "pruning loop unavailable")?;
+    Ok(serde_json::json!({}))
+}
+
+fn init_info(options: LightningOptions, configuration: Configuration) -> InitInfo {
+    InitInfo {
+        socket_path: configuration.rpc_path(),
+        pruning_interval: options.pruning_interval,
+        dry_run: options.pruning_dry_run,
+        block_batch: options.pruning_block_batch,
+        min_verification_progress: options.pruning_min_verification_progress,
+        max_sync_gap: options.pruning_max_sync_gap,
+        anomaly_gap: options.pruning_anomaly_gap,
+        target_mb: options.pruning_target_mb,
+        history_limit: options.pruning_history_limit,
+        metrics_bind: options.pruning_metrics_bind,
+        metrics_textfile: options.pruning_metrics_textfile,
+        bitcoin: options.bitcoin,
+        network: configuration.network.map(Network::from),
+        proxy: configuration
+            .proxy
+            .map(|p| format!("{}:{}", p.address, p.port)),
+        always_use_proxy: configuration.always_use_proxy,
+    }
+}
+
+fn default_pruning_interval() -> u64 {
+    600
+}
+
+fn default_pruning_block_batch() -> u64 {
+    1
+}
+
+fn default_pruning_min_verification_progress() -> f64 {
+    0.9999
+}
+
+fn default_pruning_max_sync_gap() -> u64 {
+    6
+}
+
+fn default_pruning_anomaly_gap() -> u64 {
+    144
+}
+
+fn default_pruning_history_limit() -> u64 {
+    1000
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LightningOptions {
+    #[serde(default = "default_pruning_interval")]
+    #[serde(deserialize_with = "deser_str_num")]
+    pruning_interval: u64,
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_str_bool")]
+    pruning_dry_run: bool,
+    #[serde(default = "default_pruning_block_batch")]
+    #[serde(deserialize_with = "deser_str_num")]
+    pruning_block_batch: u64,
+    #[serde(default = "default_pruning_min_verification_progress")]
+    #[serde(deserialize_with = "deser_str_float")]
+    pruning_min_verification_progress: f64,
+    #[serde(default = "default_pruning_max_sync_gap")]
+    #[serde(deserialize_with = "deser_str_num")]
+    pruning_max_sync_gap: u64,
+    #[serde(default = "default_pruning_anomaly_gap")]
+    #[serde(deserialize_with = "deser_str_num")]
+    pruning_anomaly_gap: u64,
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_str_num")]
+    pruning_target_mb: u64,
+    #[serde(default = "default_pruning_history_limit")]
+    #[serde(deserialize_with = "deser_str_num")]
+    pruning_history_limit: u64,
+    #[serde(default)]
+    pruning_metrics_bind: Option<SocketAddr>,
+    #[serde(default)]
+    pruning_metrics_textfile: Option<PathBuf>,
+    #[serde(flatten)]
+    bitcoin: BitcoinInfo,
+}
+
+impl LightningOptions {
+    fn validate(&self) -> Result<(), String> {
+        ConfigUpdate::Interval(self.pruning_interval).validate()?;
+        ConfigUpdate::MinVerificationProgress(self.pruning_min_verification_progress).validate()
+    }
+}
+
+fn deser_str_num<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum StrNum {
+        Str(String),
+        Num(u64),
+    }
+    let sn: StrNum = serde::Deserialize::deserialize(deserializer)?;
+    Ok(match sn {
+        StrNum::Str(s) => s.parse().map_err(serde::de::Error::custom)?,
+        StrNum::Num(n) => n,
+    })
+}
+
+fn deser_str_float<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum StrFloat {
+        Str(String),
+        Float(f64),
+    }
+    let sf: StrFloat = serde::Deserialize::deserialize(deserializer)?;
+    Ok(match sf {
+        StrFloat::Str(s) => s.parse().map_err(serde::de::Error::custom)?,
+        StrFloat::Float(f) => f,
+    })
+}
+
+fn deser_str_bool<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum StrBool {
+        Str(String),
+        Bool(bool),
+    }
+    let sb: StrBool = serde::Deserialize::deserialize(deserializer)?;
+    Ok(match sb {
+        StrBool::Str(s) => s.parse().map_err(serde::de::Error::custom)?,
+        StrBool::Bool(b) => b,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setconfig() {
+        let (commands, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let by_name = |config: &str, val: Value| {
+            let mut params = serde_json::Map::new();
+            params.insert("config".to_owned(), config.into());
+            params.insert("val".to_owned(), val);
+            handle_setconfig(&commands, &RpcParams::ByName(params))
+        };
+        by_name("pruning-interval", 60.into()).unwrap();
+        by_name("pruning-dry-run", "true".into()).unwrap();
+        handle_setconfig(
+            &commands,
+            &RpcParams::ByPosition(vec!["pruning-min-verification-progress".into(), 0.5.into()]),
+        )
+        .unwrap();
+        for _ in 0..3 {
+            match receiver.try_recv().unwrap() {
+                PruningCommand::SetConfig(ConfigUpdate::Interval(60))
+                | PruningCommand::SetConfig(ConfigUpdate::DryRun(true)) => (),
+                PruningCommand::SetConfig(ConfigUpdate::MinVerificationProgress(p))
+                    if (p - 0.5).abs() < f64::EPSILON => {}
+                cmd => panic!("unexpected command {:?}", cmd),
+            }
+        }
+
+        for (config, val) in [
+            ("pruning-interval", Value::from(0)),
+            ("pruning-interval", Value::from("soon")),
+            ("pruning-interval", Value::from(u64::MAX)),
+            ("pruning-min-verification-progress", Value::from(1.5)),
+            ("pruning-unknown", Value::from(1)),
+        ] {
+            assert_eq!(by_name(config, val).unwrap_err().code, 8.into());
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration(&Value::from(90)), Ok(90));
+        assert_eq!(parse_duration(&Value::from("90")), Ok(90));
+        assert_eq!(parse_duration(&Value::from("45s")), Ok(45));
+        assert_eq!(parse_duration(&Value::from("30m")), Ok(30 * 60));
+        assert_eq!(parse_duration(&Value::from(" 2h ")), Ok(2 * 60 * 60));
+        assert_eq!(parse_duration(&Value::from("1d")), Ok(24 * 60 * 60));
+        assert_eq!(parse_duration(&Value::from("365d")), Ok(MAX_PAUSE));
+        for bad in &[
+            Value::from(-1),
+            Value::from(1.5),
+            Value::from(""),
+            Value::from("h"),
+            Value::from("2w"),
+            Value::from(true),
+            Value::from(u64::MAX),
+            Value::from("366d"),
+            Value::from("18446744073709551615"),
+        ] {
+            assert!(parse_duration(bad).is_err(), "{} parsed", bad);
+        }
+    }
+}
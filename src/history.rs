@@ -0,0 +1,185 @@
+use failure::Error;
+
+use crate::client::LightningClient;
+use crate::datastore;
+use crate::pruning::PruneReport;
+use crate::status::unix_now;
+
+const HISTORY_KEY: &str = "history";
+
+/// A record of one `pruneblockchain` call
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct HistoryEntry {
+    /// unix timestamp of the call
+    pub timestamp: u64,
+    /// lightningd's block height at the time
+    pub blockheight: u64,
+    /// height requested from `pruneblockchain`
+    pub prune_height: u64,
+    /// height bitcoind reported having pruned to
+    pub pruned_height: Option<u64>,
+}
+
+/// A page of history, newest first
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct HistoryPage {
+    pub history: Vec<HistoryEntry>,
+    /// number of entries stored
+    pub total: usize,
+}
+
+/// Returns the stored entries, oldest first
+async fn entries(client: &LightningClient) -> Result<Vec<(String, HistoryEntry)>, Error> {
+    let mut entries = Vec::new();
+    for entry in datastore::list(client, &[HISTORY_KEY]).await? {
+        let (name, value) = match (entry.key.get(2), &entry.string) {
+            (Some(name), Some(value)) if entry.key.len() == 3 => (name, value),
+            _ => continue,
+        };
+        match serde_json::from_str(value) {
+            Ok(e) => entries.push((name.clone(), e)),
+            Err(e) => log::warn!("ignoring malformed history entry {}: {}", name, e),
+        }
+    }
+    // names start with the zero-padded timestamp, so they sort chronologically
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+/// Stores a `pruneblockchain` call, then drops the oldest entries beyond `limit`
+pub async fn record(
+    client: &LightningClient,
+    report: &PruneReport,
+    limit: u64,
+) -> Result<(), Error> {
+    let prune_height = match report.prune_height {
+        Some(height) if limit > 0 && report.pruned_height.is_some() => height,
+        _ => return Ok(()),
+    };
+    let entry = HistoryEntry {
+        timestamp: unix_now(),
+        blockheight: report.blockheight,
+        prune_height,
+        pruned_height: report.pruned_height,
+    };
+    let name = format!("{:010}-{}", entry.timestamp, entry.prune_height);
+    datastore::set(
+        client,
+        &[HISTORY_KEY, &name],
+        &serde_json::to_string(&entry)?,
+    )
+    .await?;
+    let entries = entries(client).await?;
+    let excess = entries.len().saturating_sub(limit as usize);
+    for (name, _) in &entries[..excess] {
+        datastore::del(client, &[HISTORY_KEY, name]).await?;
+    }
+    Ok(())
+}
+
+/// Returns up to `limit` entries, newest first, skipping the `offset` newest
+pub async fn page(
+    client: &LightningClient,
+    offset: usize,
+    limit: usize,
+) -> Result<HistoryPage, Error> {
+    let entries = entries(client).await?;
+    Ok(HistoryPage {
+        total: entries.len(),
+        history: entries
+            .into_iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, e)| e)
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use c_lightning_pruning_plugin::async_io::RpcResponseStream;
+    use c_lightning_pruning_plugin::rpc::{RpcReq, RpcRes};
+    use serde_json::Value;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixStream;
+    use tokio::stream::StreamExt;
+
+    use super::*;
+
+    /// answers datastore requests from an in-memory map until the client hangs up
+    async fn mock_datastore(stream: UnixStream) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reqs = RpcResponseStream::new(reader);
+        let mut store = BTreeMap::<Vec<String>, String>::new();
+        while let Some(Ok(req)) = reqs.next().await {
+            let req: RpcReq = serde_json::from_value(req).unwrap();
+            let params = serde_json::to_value(&req.params).unwrap();
+            let key: Vec<String> = serde_json::from_value(params["key"].clone()).unwrap();
+            let result = match req.method.as_ref() {
+                "listdatastore" => {
+                    let entries: Vec<Value> = store
+                        .iter()
+                        .filter(|(k, _)| k.starts_with(&key))
+                        .map(|(k, v)| serde_json::json!({ "key": k, "string": v }))
+                        .collect();
+                    serde_json::json!({ "datastore": entries })
+                }
+                "datastore" => {
+                    store.insert(key, params["string"].as_str().unwrap().to_owned());
+                    serde_json::json!({})
+                }
+                "deldatastore" => {
+                    store.remove(&key);
+                    serde_json::json!({})
+                }
+                method => panic!("unexpected method {}", method),
+            };
+            let res = serde_json::to_vec(&RpcRes {
+                id: req.id.unwrap(),
+                jsonrpc: Default::default(),
+                result: Ok(result).into(),
+            })
+            .unwrap();
+            writer.write_all(&res).await.unwrap();
+            writer.write_all(b"\n\n").await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn retention_and_paging() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let client = LightningClient::new(client);
+        tokio::spawn(mock_datastore(server));
+        for height in 100..105 {
+            let report = PruneReport {
+                blockheight: height + 10,
+                prune_height: Some(height),
+                pruned_height: Some(height),
+                ..Default::default()
+            };
+            record(&client, &report, 3).await.unwrap();
+        }
+        // dry runs aren't recorded
+        let dry_run = PruneReport {
+            prune_height: Some(200),
+            dry_run: true,
+            ..Default::default()
+        };
+        record(&client, &dry_run, 3).await.unwrap();
+
+        let all = page(&client, 0, 10).await.unwrap();
+        assert_eq!(all.total, 3);
+        let heights: Vec<u64> = all.history.iter().map(|e| e.prune_height).collect();
+        assert_eq!(heights, vec![104, 103, 102]);
+        assert_eq!(all.history[0].pruned_height, Some(104));
+        assert_eq!(all.history[0].blockheight, 114);
+
+        let second = page(&client, 1, 1).await.unwrap();
+        assert_eq!(second.total, 3);
+        assert_eq!(second.history.len(), 1);
+        assert_eq!(second.history[0].prune_height, 103);
+    }
+}
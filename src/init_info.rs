@@ -7,18 +7,48 @@ use serde_json::Value;
 use tokio::sync::RwLock;
 use url::Host;
 
+use crate::bitcoin::BitcoinAuth;
+
 #[derive(Clone, Debug)]
 pub struct BitcoinInfo {
-    pub bitcoin_rpcuser: String,
-    pub bitcoin_rpcpassword: String,
+    pub bitcoin_datadir: Option<PathBuf>,
+    pub bitcoin_rpcuser: Option<String>,
+    pub bitcoin_rpcpassword: Option<String>,
     pub bitcoin_rpcconnect: Host<String>,
     pub bitcoin_rpcport: Option<u16>,
 }
+impl BitcoinInfo {
+    /// Uses the configured rpcuser/rpcpassword if either is set, otherwise bitcoind's cookie file,
+    /// falling back to the legacy default credentials if there is no datadir to find it in
+    pub fn auth(&self, network: &Network) -> BitcoinAuth {
+        if self.bitcoin_rpcuser.is_some() || self.bitcoin_rpcpassword.is_some() {
+            return BitcoinAuth::UserPass(
+                self.bitcoin_rpcuser
+                    .clone()
+                    .unwrap_or_else(|| "bitcoin".to_owned()),
+                self.bitcoin_rpcpassword
+                    .clone()
+                    .unwrap_or_else(|| "local321".to_owned()),
+            );
+        }
+        let datadir = self
+            .bitcoin_datadir
+            .clone()
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".bitcoin")));
+        match datadir {
+            Some(datadir) => {
+                BitcoinAuth::Cookie(datadir.join(network.datadir_name()).join(".cookie"))
+            }
+            None => BitcoinAuth::UserPass("bitcoin".to_owned(), "local321".to_owned()),
+        }
+    }
+}
 impl<'de> serde::Deserialize<'de> for BitcoinInfo {
     fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
         #[serde(rename_all = "kebab-case")]
         pub struct BitcoinInfoSerDe {
+            pub bitcoin_datadir: Option<PathBuf>,
             pub bitcoin_rpcuser: Option<String>,
             pub bitcoin_rpcpassword: Option<String>,
             pub bitcoin_rpcconnect: Option<String>,
@@ -27,10 +57,9 @@ impl<'de> serde::Deserialize<'de> for BitcoinInfo {
 
         let info: BitcoinInfoSerDe = serde::Deserialize::deserialize(deserializer)?;
         Ok(BitcoinInfo {
-            bitcoin_rpcuser: info.bitcoin_rpcuser.unwrap_or_else(|| "bitcoin".to_owned()),
-            bitcoin_rpcpassword: info
-                .bitcoin_rpcpassword
-                .unwrap_or_else(|| "local321".to_owned()),
+            bitcoin_datadir: info.bitcoin_datadir,
+            bitcoin_rpcuser: info.bitcoin_rpcuser,
+            bitcoin_rpcpassword: info.bitcoin_rpcpassword,
             bitcoin_rpcconnect: info
                 .bitcoin_rpcconnect
                 .map(|a| Host::parse(&a))
@@ -50,19 +79,67 @@ pub struct PluginInfo {
     pub options: Value,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(from = "String", into = "String")]
 pub enum Network {
     Regtest,
     Testnet,
+    Testnet4,
+    Signet,
     Bitcoin,
+    /// any network this plugin doesn't know the defaults for
+    Other(String),
+}
+impl From<String> for Network {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "regtest" => Network::Regtest,
+            "testnet" => Network::Testnet,
+            "testnet4" => Network::Testnet4,
+            "signet" => Network::Signet,
+            "bitcoin" => Network::Bitcoin,
+            _ => Network::Other(s),
+        }
+    }
+}
+impl From<Network> for String {
+    fn from(n: Network) -> Self {
+        n.to_string()
+    }
+}
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Network::Regtest => write!(f, "regtest"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Testnet4 => write!(f, "testnet4"),
+            Network::Signet => write!(f, "signet"),
+            Network::Bitcoin => write!(f, "bitcoin"),
+            Network::Other(s) => write!(f, "{}", s),
+        }
+    }
 }
 impl Network {
-    pub fn default_port(&self) -> u16 {
+    /// bitcoind's default RPC port, unknown networks require an explicit `bitcoin-rpcport`
+    pub fn default_port(&self) -> Option<u16> {
         match self {
-            &Network::Regtest => 18443,
-            &Network::Testnet => 18332,
-            &Network::Bitcoin => 8332,
+            Network::Regtest => Some(18443),
+            Network::Testnet => Some(18332),
+            Network::Testnet4 => Some(48332),
+            Network::Signet => Some(38332),
+            Network::Bitcoin => Some(8332),
+            Network::Other(_) => None,
+        }
+    }
+    /// subdirectory of bitcoind's datadir used for this network
+    pub fn datadir_name(&self) -> &str {
+        match self {
+            Network::Regtest => "regtest",
+            Network::Testnet => "testnet3",
+            Network::Testnet4 => "testnet4",
+            Network::Signet => "signet",
+            Network::Bitcoin => "",
+            Network::Other(s) => s,
         }
     }
 }
@@ -72,7 +149,7 @@ impl Network {
 pub struct ConfigInfo {
     pub network: Network,
     pub always_use_proxy: bool,
-    pub rescan: u64,
+    pub rescan: i64,
     pub proxy: Option<SocketAddr>,
     pub plugins: Vec<PluginInfo>,
 }
@@ -81,6 +158,21 @@ pub struct ConfigInfo {
 pub struct InitInfo {
     pub socket_path: PathBuf,
     pub pruning_interval: u64,
+    pub dry_run: bool,
+    pub block_batch: u64,
+    pub min_verification_progress: f64,
+    pub max_sync_gap: u64,
+    pub anomaly_gap: u64,
+    pub target_mb: u64,
+    pub history_limit: u64,
+    pub metrics_bind: Option<SocketAddr>,
+    pub metrics_textfile: Option<PathBuf>,
+    /// our own `bitcoin-*` options, only registered in backend mode
+    pub bitcoin: BitcoinInfo,
+    pub network: Option<Network>,
+    /// `address:port` of lightningd's proxy
+    pub proxy: Option<String>,
+    pub always_use_proxy: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -105,16 +197,15 @@ impl InitInfoArc {
             let guard = self.state.read().await;
             match &*guard {
                 InitInfoState::Resolved(ref path) => return path.clone(),
-                InitInfoState::Waiting(receiver) => match receiver.try_recv() {
-                    Ok(ii) => {
+                InitInfoState::Waiting(receiver) => {
+                    if let Ok(ii) = receiver.try_recv() {
                         let arc_ii = Arc::new(ii);
                         drop(guard); // turns out this is important
                         let mut guard = self.state.write().await;
                         *guard = InitInfoState::Resolved(arc_ii.clone());
                         return arc_ii;
                     }
-                    Err(_) => (),
-                },
+                }
             }
         }
     }
@@ -0,0 +1,10 @@
+//! The plugin protocol spoken with c-lightning, shared by the pruning plugin and usable for others
+//!
+//! [`plugin::Builder`] registers options, methods, subscriptions and hooks, and the [`plugin::Plugin`]
+//! it builds answers `getmanifest` and `init` and dispatches everything else read from stdin.
+//! Responses and notifications go out through a [`stdio::StdoutWriter`].
+
+pub mod async_io;
+pub mod plugin;
+pub mod rpc;
+pub mod stdio;
@@ -0,0 +1,52 @@
+use std::borrow::Cow;
+
+use c_lightning_pruning_plugin::rpc::{RpcParams, RpcReq};
+use c_lightning_pruning_plugin::stdio::StdoutWriter;
+use log::{Level, Metadata, Record};
+
+/// Forwards log records to lightningd as `log` notifications, so they end up in lightningd's log
+/// tagged with the plugin name and filtered by its `log-level`
+pub struct LightningLogger {
+    writer: StdoutWriter,
+}
+impl log::Log for LightningLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // keep debug output from dependencies out of lightningd's log
+        if metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            metadata.level() <= Level::Debug
+        } else {
+            metadata.level() <= Level::Info
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => "broken",
+            Level::Warn => "unusual",
+            Level::Info => "info",
+            Level::Debug | Level::Trace => "debug",
+        };
+        let mut params = serde_json::Map::new();
+        params.insert("level".to_owned(), level.into());
+        params.insert("message".to_owned(), format!("{}", record.args()).into());
+        self.writer
+            .write(&RpcReq {
+                id: None,
+                jsonrpc: Default::default(),
+                method: Cow::Borrowed("log"),
+                params: RpcParams::ByName(params),
+            })
+            .unwrap_or_else(|e| eprintln!("unable to write log notification: {}", e));
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(writer: StdoutWriter) -> Result<(), failure::Error> {
+    log::set_boxed_logger(Box::new(LightningLogger { writer }))?;
+    log::set_max_level(log::LevelFilter::Debug);
+    Ok(())
+}
@@ -1,13 +1,21 @@
-use std::borrow::Cow;
+use std::sync::Arc;
 
+use c_lightning_pruning_plugin::{rpc, stdio};
 use failure::Error;
 use tokio::stream::StreamExt;
 
-mod async_io;
+mod backend;
+mod bitcoin;
+mod client;
+mod datastore;
+mod handlers;
+mod history;
 mod init_info;
+mod logger;
+mod metrics;
 mod pruning;
-mod rpc;
-mod stdio;
+mod refetch;
+mod status;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
@@ -19,52 +27,22 @@ fn is_onion(url: &reqwest::Url) -> bool {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    simple_logging::log_to_stderr(log::LevelFilter::Info); // set up logging
-
-    // start rpc handler and wait for info needed from "init" method
-    let (sender, reciever) = crossbeam_channel::bounded(1);
-    let rpc_handler = std::thread::spawn(move || stdio::run_rpc_handler(sender));
-    let init_info = init_info::InitInfoArc::new(reciever).wait_for_info().await;
-
-    // connect an RPC socket to be reused for rpc requests
-    let mut socket = tokio::net::UnixStream::connect(&*init_info.socket_path).await?;
-
-    // fetch configuration params external to the plugin
-    let config_info = rpc::make_socket_req(
-        &mut socket,
-        rpc::RpcReq {
-            id: Some(rpc::JsonRpcV2Id::Num(0.into())),
-            jsonrpc: Default::default(),
-            method: Cow::Borrowed("listconfigs"),
-            params: rpc::RpcParams::ByPosition(Vec::new()),
-        },
-    )
-    .await?
-    .result
-    .res()?;
-    let config_info: init_info::ConfigInfo = serde_json::from_value(config_info)?;
-    let bitcoin_info: init_info::BitcoinInfo = serde_json::from_value(
-        config_info
-            .plugins
-            .into_iter()
-            .filter(|a| &a.name == "bcli")
-            .next()
-            .ok_or_else(|| failure::format_err!("bcli info not found"))?
-            .options,
-    )?;
-
-    // create an http request to bitcoind that can be cloned and reused
+/// Creates an http client for bitcoind that can be reused
+fn bitcoin_client(
+    bitcoin_info: &init_info::BitcoinInfo,
+    network: &init_info::Network,
+    proxy: Option<String>,
+    always_use_proxy: bool,
+) -> Result<bitcoin::BitcoinClient, Error> {
     let client = reqwest::Client::builder().user_agent(APP_USER_AGENT);
-    let client = if let Some(proxy) = config_info.proxy {
+    let client = if let Some(proxy) = proxy {
         // use provided socks5 proxy if necessary
         let proxy = reqwest::Url::parse(&format!("socks5h://{}", proxy))?;
-        client.proxy(if config_info.always_use_proxy {
+        client.proxy(if always_use_proxy {
             reqwest::Proxy::all(proxy)?
         } else {
             reqwest::Proxy::custom(move |url| {
-                if is_onion(&url) {
+                if is_onion(url) {
                     Some(proxy.clone())
                 } else {
                     None
@@ -77,29 +55,384 @@ async fn main() -> Result<(), Error> {
     let client = client.build()?;
     let mut bitcoin_url = reqwest::Url::parse("http://localhost")?;
     bitcoin_url.set_host(Some(&format!("{}", bitcoin_info.bitcoin_rpcconnect)))?;
+    let bitcoin_port = bitcoin_info
+        .bitcoin_rpcport
+        .or_else(|| network.default_port())
+        .ok_or_else(|| {
+            failure::format_err!("bitcoin-rpcport is required for network {}", network)
+        })?;
     bitcoin_url
-        .set_port(Some(
-            bitcoin_info
-                .bitcoin_rpcport
-                .unwrap_or(config_info.network.default_port()),
-        ))
+        .set_port(Some(bitcoin_port))
         .map_err(|_| failure::format_err!("unable to set port"))?;
-    let bitcoin_req = client.post(bitcoin_url).basic_auth(
-        bitcoin_info.bitcoin_rpcuser,
-        Some(bitcoin_info.bitcoin_rpcpassword),
-    );
-
-    // every `pruning-interval` seconds, run the `prune` method
-    let mut interval =
-        tokio::time::interval(std::time::Duration::from_secs(init_info.pruning_interval));
-    while let Some(_) = interval.next().await {
-        match pruning::prune(&mut socket, &bitcoin_req, config_info.rescan).await {
-            Ok(_) => (),
-            Err(e) => log::error!("{}", e),
+    let bitcoin_auth = bitcoin_info.auth(network);
+    if let bitcoin::BitcoinAuth::Cookie(path) = &bitcoin_auth {
+        log::info!("using bitcoind cookie at {}", path.display());
+    }
+    Ok(bitcoin::BitcoinClient::new(
+        client,
+        bitcoin_url,
+        bitcoin_auth,
+    ))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let writer = stdio::StdoutWriter::spawn();
+    logger::init(writer.clone())?; // forward logs to lightningd
+
+    // start rpc handler and wait for info needed from "init" method
+    let (sender, reciever) = crossbeam_channel::bounded(1);
+    let status = status::StatusArc::default();
+    let (commands, mut command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let shutdown = Arc::new(tokio::sync::Notify::new());
+    let (backend, backend_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let ctx = handlers::RpcContext {
+        init_sender: sender,
+        status: status.clone(),
+        commands,
+        shutdown: shutdown.clone(),
+        backend,
+    };
+    // the handler thread blocks on stdin, so it is left running when main returns
+    let plugin = handlers::builder(ctx).build(writer.clone());
+    let stdin_closed = shutdown.clone();
+    std::thread::spawn(move || {
+        plugin.run();
+        // stdin is closed: lightningd has gone away
+        stdin_closed.notify();
+    });
+    let init_info = tokio::select! {
+        init_info = init_info::InitInfoArc::new(reciever).wait_for_info() => init_info,
+        _ = shutdown.notified() => return Ok(()),
+    };
+
+    if let Some(addr) = init_info.metrics_bind {
+        let status = status.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, status).await {
+                log::error!("unable to serve metrics on {}: {}", addr, e);
+            }
+        });
+    }
+    if let Some(path) = init_info.metrics_textfile.clone() {
+        tokio::spawn(metrics::run_textfile(path, status.clone()));
+    }
+
+    // as the backend, lightningd waits on us before it answers RPC requests, so only use what
+    // came with "init"
+    let backend_bitcoin = if cfg!(feature = "backend") {
+        let network = init_info
+            .network
+            .as_ref()
+            .ok_or_else(|| failure::format_err!("lightningd did not send its network"))?;
+        let bitcoin = Arc::new(bitcoin_client(
+            &init_info.bitcoin,
+            network,
+            init_info.proxy.clone(),
+            init_info.always_use_proxy,
+        )?);
+        tokio::spawn(backend::run(
+            bitcoin.clone(),
+            status.clone(),
+            backend_receiver,
+        ));
+        Some(bitcoin)
+    } else {
+        None
+    };
+
+    // connect an RPC client to be shared for rpc requests
+    let lightning = loop {
+        match client::LightningClient::connect(&init_info.socket_path).await {
+            Ok(lightning) => break lightning.with_status(status.clone()),
+            // lightningd may not be listening until its backend has answered
+            Err(e) if backend_bitcoin.is_some() => {
+                log::debug!("waiting for lightningd RPC socket: {}", e);
+                tokio::select! {
+                    _ = tokio::time::delay_for(std::time::Duration::from_secs(1)) => (),
+                    _ = shutdown.notified() => return Ok(()),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    // fetch configuration params external to the plugin
+    let setup = async {
+        let config_info = lightning
+            .call("listconfigs", rpc::RpcParams::ByPosition(Vec::new()))
+            .await??;
+        let config_info: init_info::ConfigInfo = serde_json::from_value(config_info)?;
+        let bitcoin = match backend_bitcoin.clone() {
+            Some(bitcoin) => bitcoin,
+            None => {
+                let bitcoin_info: init_info::BitcoinInfo = serde_json::from_value(
+                    config_info
+                        .plugins
+                        .into_iter()
+                        .find(|a| a.name == "bcli")
+                        .ok_or_else(|| {
+                            failure::format_err!(
+                                "bcli info not found, build with --features backend to replace it"
+                            )
+                        })?
+                        .options,
+                )?;
+                Arc::new(bitcoin_client(
+                    &bitcoin_info,
+                    &config_info.network,
+                    config_info.proxy.map(|p| p.to_string()),
+                    config_info.always_use_proxy,
+                )?)
+            }
+        };
+        Ok::<_, Error>((config_info.rescan, bitcoin))
+    };
+    let (rescan, bitcoin) = match setup.await {
+        Ok(setup) => setup,
+        // lightningd can't run without its backend, so keep serving it without pruning
+        Err(e) if backend_bitcoin.is_some() => {
+            log::error!("pruning disabled: {}", e);
+            status.update(|s| s.last_error = Some(format!("pruning disabled: {}", e)));
+            loop {
+                tokio::select! {
+                    _ = shutdown.notified() => break,
+                    Some(cmd) = command_receiver.recv() => {
+                        cmd.reject(format!("pruning disabled: {}", e))
+                    }
+                }
+            }
+            log::info!("shutting down");
+            writer.flush();
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut policy = pruning::PruningPolicy {
+        rescan,
+        dry_run: init_info.dry_run,
+        min_verification_progress: init_info.min_verification_progress,
+        max_sync_gap: init_info.max_sync_gap,
+        anomaly_gap: init_info.anomaly_gap,
+        target_mb: init_info.target_mb,
+    };
+    status.update(|s| {
+        s.rescan = Some(policy.rescan);
+        s.dry_run = policy.dry_run;
+    });
+
+    // check that bitcoind will accept `pruneblockchain` before we start calling it
+    match bitcoin::get_blockchain_info(&bitcoin).await {
+        Ok(info) => {
+            pruning::log_prune_mode(&info);
+            status.update(|s| s.bitcoind_prune_mode = Some(info.prune_mode()));
+            // lightningd may have been restarted with a deeper `rescan` than blocks were kept for
+            match pruning::rescan_gap(&lightning, &info, policy.rescan).await {
+                Ok(Some((from, to))) => {
+                    log::warn!(
+                        "blocks {} to {} that lightningd may rescan have been pruned",
+                        from,
+                        to
+                    );
+                    if let Err(e) = refetch::spawn(bitcoin.clone(), status.clone(), from, to) {
+                        log::error!("{}", e);
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => log::error!("unable to check for pruned blocks: {}", e),
+            }
+        }
+        Err(e) => log::error!("unable to query bitcoind: {}", e),
+    }
+
+    // a pause survives restarts, so pruning doesn't silently resume mid-maintenance
+    let mut paused = None;
+    match pruning::load_pause(&lightning).await {
+        Ok(Some(state)) => {
+            log::warn!(
+                "pruning has been paused since {}, call pruning-resume to resume",
+                state.since
+            );
+            status.update(|s| s.paused = Some(state.clone()));
+            paused = Some(state);
+        }
+        Ok(None) => (),
+        Err(e) => log::error!("unable to read pause state from datastore: {}", e),
+    }
+
+    // every `pruning-block-batch` new blocks, falling back to every `pruning-interval` seconds,
+    // or whenever `pruning-now` is called, run the `prune` method
+    let mut pruning_interval = init_info.pruning_interval;
+    let mut block_batch = init_info.block_batch;
+    let mut history_limit = init_info.history_limit;
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(pruning_interval));
+    let mut blocks_since_prune = 0;
+    loop {
+        let resume_in = paused
+            .as_ref()
+            .and_then(|p| p.until)
+            .map(|until| std::time::Duration::from_secs(until.saturating_sub(status::unix_now())));
+        let (reply, dry_run) = tokio::select! {
+            _ = shutdown.notified() => break,
+            _ = tokio::time::delay_for(resume_in.unwrap_or_default()), if resume_in.is_some() => {
+                pruning::set_pause(&lightning, &status, &mut paused, None).await;
+                continue;
+            }
+            tick = interval.next() => match tick {
+                Some(_) => {
+                    status.update(|s| {
+                        s.next_run = Some(status::unix_now() + pruning_interval)
+                    });
+                    (None, None)
+                }
+                None => break,
+            },
+            Some(cmd) = command_receiver.recv() => match cmd {
+                pruning::PruningCommand::PruneNow { dry_run, reply } => {
+                    // dry runs don't touch bitcoind's blocks, so they're fine while paused
+                    if paused.is_some() && !dry_run.unwrap_or(policy.dry_run) {
+                        reply.send(Err("pruning is paused, call pruning-resume first".to_owned()));
+                        continue;
+                    }
+                    (Some(reply), dry_run)
+                }
+                pruning::PruningCommand::BlockAdded(height) => {
+                    blocks_since_prune += 1;
+                    if block_batch == 0 || blocks_since_prune < block_batch {
+                        continue;
+                    }
+                    log::debug!("block {} added, running pruning check", height);
+                    (None, None)
+                }
+                pruning::PruningCommand::SetConfig(update) => {
+                    log::info!("config updated: {:?}", update);
+                    match update {
+                        handlers::ConfigUpdate::Interval(secs) => {
+                            // restart the timer so the new interval counts from now
+                            pruning_interval = secs;
+                            let period = std::time::Duration::from_secs(secs);
+                            interval =
+                                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                            status.update(|s| s.next_run = Some(status::unix_now() + secs));
+                        }
+                        handlers::ConfigUpdate::DryRun(dry_run) => {
+                            policy.dry_run = dry_run;
+                            status.update(|s| s.dry_run = dry_run);
+                        }
+                        handlers::ConfigUpdate::BlockBatch(n) => block_batch = n,
+                        handlers::ConfigUpdate::MinVerificationProgress(p) => {
+                            policy.min_verification_progress = p
+                        }
+                        handlers::ConfigUpdate::MaxSyncGap(n) => policy.max_sync_gap = n,
+                        handlers::ConfigUpdate::AnomalyGap(n) => policy.anomaly_gap = n,
+                        handlers::ConfigUpdate::TargetMb(n) => policy.target_mb = n,
+                        handlers::ConfigUpdate::HistoryLimit(n) => history_limit = n,
+                    }
+                    continue;
+                }
+                pruning::PruningCommand::Pause { duration, reply } => {
+                    let now = status::unix_now();
+                    let state = pruning::PauseState {
+                        since: paused.as_ref().map_or(now, |p| p.since),
+                        until: duration.map(|d| now.saturating_add(d)),
+                    };
+                    let report = pruning::set_pause(&lightning, &status, &mut paused, Some(state)).await;
+                    reply.send(Ok(report));
+                    continue;
+                }
+                pruning::PruningCommand::Refetch { from, to, reply } => {
+                    reply.send(refetch::spawn(bitcoin.clone(), status.clone(), from, to));
+                    continue;
+                }
+                pruning::PruningCommand::History { offset, limit, reply } => {
+                    // reading the datastore needn't hold up the loop
+                    let lightning = lightning.clone();
+                    tokio::spawn(async move {
+                        let page = history::page(&lightning, offset, limit)
+                            .await
+                            .map_err(|e| format!("{}", e));
+                        reply.send(page);
+                    });
+                    continue;
+                }
+                pruning::PruningCommand::Resume { reply } => {
+                    let report = pruning::set_pause(&lightning, &status, &mut paused, None).await;
+                    reply.send(Ok(report));
+                    continue;
+                }
+            },
+        };
+        if paused.is_some() && reply.is_none() {
+            log::debug!("pruning is paused, skipping scheduled check");
+            continue;
+        }
+        blocks_since_prune = 0;
+        let run_policy = pruning::PruningPolicy {
+            dry_run: dry_run.unwrap_or(policy.dry_run),
+            ..policy.clone()
+        };
+        let res = tokio::select! {
+            res = pruning::prune(&lightning, &bitcoin, &run_policy) => res.map_err(|e| format!("{}", e)),
+            _ = shutdown.notified() => {
+                log::info!("cancelling pruning check in progress");
+                break;
+            }
+        };
+        if let Ok(report) = &res {
+            if let Err(e) = history::record(&lightning, report, history_limit).await {
+                log::warn!("unable to record prune history: {}", e);
+                status.count_error("history");
+            }
+        }
+        status.update(|s| {
+            s.last_run = Some(status::unix_now());
+            match &res {
+                Ok(report) => {
+                    if report.prune_mode.is_some() && report.prune_mode != s.bitcoind_prune_mode {
+                        log::warn!(
+                            "bitcoind prune mode changed from {:?} to {:?}",
+                            s.bitcoind_prune_mode,
+                            report.prune_mode
+                        );
+                        s.bitcoind_prune_mode = report.prune_mode;
+                    }
+                    if report.skipped != s.skipped {
+                        if let Some(skipped) = &report.skipped {
+                            log::info!("deferring pruning: {}", skipped);
+                        }
+                    }
+                    if report.anomaly != s.anomaly {
+                        if let Some(anomaly) = &report.anomaly {
+                            log::warn!("anomaly: {}", anomaly);
+                        }
+                    }
+                    s.skipped = report.skipped.clone();
+                    s.anomaly = report.anomaly.clone();
+                    s.blockheight = Some(report.blockheight);
+                    s.size_on_disk = report.size_on_disk.or(s.size_on_disk);
+                    s.prune_height = report.prune_height.or(s.prune_height);
+                    s.pruned_height = report.pruned_height.or(s.pruned_height);
+                    s.bitcoind_pruneheight = report.bitcoind_pruneheight.or(s.bitcoind_pruneheight);
+                    if report.pruned_height.is_some() {
+                        s.last_prune = s.last_run;
+                    }
+                    s.last_error = None;
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                    s.last_error = Some(e.clone());
+                    s.count_error("prune");
+                }
+            }
+        });
+        if let Some(reply) = reply {
+            reply.send(res);
         }
     }
 
-    rpc_handler.join().unwrap();
+    log::info!("shutting down");
+    writer.flush();
 
     Ok(())
 }
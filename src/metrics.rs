@@ -0,0 +1,164 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use failure::Error;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server};
+
+use crate::status::{unix_now, PruningStatus, StatusArc};
+
+/// how often the textfile is rewritten
+const TEXTFILE_INTERVAL: Duration = Duration::from_secs(15);
+
+fn gauge(out: &mut String, name: &str, help: &str, value: Option<u64>) {
+    if let Some(value) = value {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+}
+
+/// Renders the status in Prometheus' text exposition format
+pub fn render(status: &PruningStatus, now: u64) -> String {
+    let mut out = String::new();
+    gauge(
+        &mut out,
+        "pruning_lightningd_blockheight",
+        "lightningd's block height at the last pruning check",
+        status.blockheight,
+    );
+    gauge(
+        &mut out,
+        "pruning_prune_height",
+        "height most recently computed to prune to",
+        status.prune_height,
+    );
+    gauge(
+        &mut out,
+        "pruning_bitcoind_pruneheight",
+        "lowest height of a complete block stored by bitcoind",
+        status.bitcoind_pruneheight,
+    );
+    gauge(
+        &mut out,
+        "pruning_bitcoind_size_on_disk_bytes",
+        "size of bitcoind's block and undo files",
+        status.size_on_disk,
+    );
+    gauge(
+        &mut out,
+        "pruning_seconds_since_last_prune",
+        "seconds since pruneblockchain last succeeded",
+        status.last_prune.map(|t| now.saturating_sub(t)),
+    );
+    gauge(
+        &mut out,
+        "pruning_paused",
+        "whether pruning is paused by pruning-pause",
+        Some(status.paused.is_some() as u64),
+    );
+    writeln!(
+        out,
+        "# HELP pruning_errors_total errors by where they occurred"
+    )
+    .unwrap();
+    writeln!(out, "# TYPE pruning_errors_total counter").unwrap();
+    for (category, count) in &status.errors {
+        writeln!(
+            out,
+            "pruning_errors_total{{category=\"{}\"}} {}",
+            category, count
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Serves the metrics over http at `addr` until the plugin exits
+pub async fn serve(addr: SocketAddr, status: StatusArc) -> Result<(), Error> {
+    let make_svc = make_service_fn(move |_| {
+        let status = status.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req| {
+                let body = render(&status.get(), unix_now());
+                async move {
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header("Content-Type", "text/plain; version=0.0.4")
+                            .body(Body::from(body))
+                            .unwrap(), // the header is valid
+                    )
+                }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    log::info!("serving metrics on {}", addr);
+    Ok(server.await?)
+}
+
+/// Replaces the file at `path` with the current metrics, so node_exporter never reads it half
+/// written
+fn write_textfile(path: &Path, status: &StatusArc) -> Result<(), Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, render(&status.get(), unix_now()))?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Rewrites the textfile at `path` periodically until the plugin exits
+pub async fn run_textfile(path: PathBuf, status: StatusArc) {
+    let mut interval = tokio::time::interval(TEXTFILE_INTERVAL);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        match write_textfile(&path, &status) {
+            Ok(()) => failing = false,
+            Err(e) => {
+                // only log the first of a run of failures
+                if !failing {
+                    log::error!("unable to write metrics to {}: {}", path.display(), e);
+                }
+                failing = true;
+                status.count_error("metrics");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_status() {
+        let mut status = PruningStatus {
+            blockheight: Some(800_000),
+            prune_height: Some(799_000),
+            last_prune: Some(1_000),
+            ..Default::default()
+        };
+        status.count_error("prune");
+        status.count_error("prune");
+        status.count_error("history");
+        let out = render(&status, 1_060);
+        let lines: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "pruning_lightningd_blockheight 800000",
+                "pruning_prune_height 799000",
+                "pruning_seconds_since_last_prune 60",
+                "pruning_paused 0",
+                "pruning_errors_total{category=\"history\"} 1",
+                "pruning_errors_total{category=\"prune\"} 2",
+            ]
+        );
+        assert!(out.contains("# TYPE pruning_errors_total counter\n"));
+    }
+}
@@ -0,0 +1,537 @@
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+use serde_json::{StreamDeserializer, Value};
+
+use crate::rpc::*;
+use crate::stdio::StdoutWriter;
+
+type MethodHandler = Box<dyn Fn(&RpcParams) -> Result<Value, RpcError> + Send>;
+type DeferredHandler = Box<dyn Fn(Responder, &RpcParams) -> Result<(), RpcError> + Send>;
+type NotificationHandler = Box<dyn Fn(&RpcParams) -> Result<(), String> + Send>;
+type InitHandler = Box<dyn Fn(Init) -> Result<(), RpcError> + Send>;
+
+enum Handler {
+    Immediate(MethodHandler),
+    /// answers through the [`Responder`] it is given, possibly from another thread
+    Deferred(DeferredHandler),
+}
+
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionType {
+    String,
+    Int,
+    Bool,
+    Flag,
+}
+
+/// A command line option registered in the manifest
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ConfigOption {
+    name: Cow<'static, str>,
+    #[serde(rename = "type")]
+    kind: OptionType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dynamic: bool,
+    description: Cow<'static, str>,
+}
+impl ConfigOption {
+    pub fn new<N, D>(name: N, kind: OptionType, description: D) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        D: Into<Cow<'static, str>>,
+    {
+        ConfigOption {
+            name: name.into(),
+            kind,
+            default: None,
+            dynamic: false,
+            description: description.into(),
+        }
+    }
+
+    pub fn with_default<T: Into<Value>>(mut self, default: T) -> Self {
+        self.default = Some(default.into());
+        self
+    }
+
+    /// lets lightningd change the option at runtime through `setconfig`
+    pub fn dynamic(mut self) -> Self {
+        self.dynamic = true;
+        self
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct RpcMethod {
+    name: Cow<'static, str>,
+    usage: Cow<'static, str>,
+    description: Cow<'static, str>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct Hook {
+    name: Cow<'static, str>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct Manifest {
+    options: Vec<ConfigOption>,
+    rpcmethods: Vec<RpcMethod>,
+    subscriptions: Vec<Cow<'static, str>>,
+    hooks: Vec<Hook>,
+    dynamic: bool,
+}
+
+/// The parameters of lightningd's `init` call
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Init {
+    /// values of the registered options, keyed by name
+    pub options: Value,
+    pub configuration: Configuration,
+}
+impl Init {
+    /// Deserializes the option values, e.g. into a struct with a field per option
+    pub fn options<T: serde::de::DeserializeOwned>(&self) -> Result<T, RpcError> {
+        serde_json::from_value(self.options.clone())
+            .map_err(|e| format!("{}", e))
+            .with_info(5, "params deserialization error")
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Configuration {
+    pub lightning_dir: PathBuf,
+    pub rpc_file: String,
+    #[serde(default)]
+    pub startup: bool,
+    #[serde(default)]
+    pub network: Option<String>,
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    #[serde(default)]
+    pub always_use_proxy: bool,
+}
+impl Configuration {
+    /// path of lightningd's RPC socket
+    pub fn rpc_path(&self) -> PathBuf {
+        self.lightning_dir.join(&self.rpc_file)
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Proxy {
+    pub address: String,
+    pub port: u16,
+}
+
+/// Answers a single request registered with [`Builder::deferred_rpcmethod`]
+#[derive(Clone, Debug)]
+pub struct Responder {
+    id: JsonRpcV2Id,
+    writer: StdoutWriter,
+}
+impl Responder {
+    pub fn id(&self) -> &JsonRpcV2Id {
+        &self.id
+    }
+
+    pub fn respond(self, result: Result<Value, RpcError>) {
+        self.writer
+            .write(&RpcRes {
+                id: self.id,
+                jsonrpc: Default::default(),
+                result: result.into(),
+            })
+            .unwrap_or_else(|e| log::error!("{}", e));
+    }
+}
+
+/// Registers what a plugin offers lightningd, and how to handle each request and notification
+///
+/// `getmanifest` and `init` are answered by the built [`Plugin`] itself.
+pub struct Builder {
+    manifest: Manifest,
+    handlers: HashMap<Cow<'static, str>, Handler>,
+    notifications: HashMap<Cow<'static, str>, NotificationHandler>,
+    init: Option<InitHandler>,
+}
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            manifest: Manifest {
+                options: Vec::new(),
+                rpcmethods: Vec::new(),
+                subscriptions: Vec::new(),
+                hooks: Vec::new(),
+                dynamic: true,
+            },
+            handlers: HashMap::new(),
+            notifications: HashMap::new(),
+            init: None,
+        }
+    }
+}
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn option(mut self, option: ConfigOption) -> Self {
+        self.manifest.options.push(option);
+        self
+    }
+
+    fn method<N, U, D>(mut self, name: N, usage: U, description: D, handler: Handler) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        U: Into<Cow<'static, str>>,
+        D: Into<Cow<'static, str>>,
+    {
+        let name = name.into();
+        self.manifest.rpcmethods.push(RpcMethod {
+            name: name.clone(),
+            usage: usage.into(),
+            description: description.into(),
+        });
+        self.handlers.insert(name, handler);
+        self
+    }
+
+    pub fn rpcmethod<N, U, D, F>(self, name: N, usage: U, description: D, handler: F) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        U: Into<Cow<'static, str>>,
+        D: Into<Cow<'static, str>>,
+        F: Fn(&RpcParams) -> Result<Value, RpcError> + Send + 'static,
+    {
+        self.method(
+            name,
+            usage,
+            description,
+            Handler::Immediate(Box::new(handler)),
+        )
+    }
+
+    /// Registers a method that is answered later through a [`Responder`], so that slow requests
+    /// don't hold up the ones behind them. If `handler` fails, the error is sent in its place.
+    pub fn deferred_rpcmethod<N, U, D, F>(
+        self,
+        name: N,
+        usage: U,
+        description: D,
+        handler: F,
+    ) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        U: Into<Cow<'static, str>>,
+        D: Into<Cow<'static, str>>,
+        F: Fn(Responder, &RpcParams) -> Result<(), RpcError> + Send + 'static,
+    {
+        self.method(
+            name,
+            usage,
+            description,
+            Handler::Deferred(Box::new(handler)),
+        )
+    }
+
+    pub fn hook<N, F>(mut self, name: N, handler: F) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        F: Fn(&RpcParams) -> Result<Value, RpcError> + Send + 'static,
+    {
+        let name = name.into();
+        self.manifest.hooks.push(Hook { name: name.clone() });
+        self.handlers
+            .insert(name, Handler::Immediate(Box::new(handler)));
+        self
+    }
+
+    pub fn subscribe<N, F>(mut self, topic: N, handler: F) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        F: Fn(&RpcParams) -> Result<(), String> + Send + 'static,
+    {
+        let topic = topic.into();
+        self.manifest.subscriptions.push(topic.clone());
+        self.notifications.insert(topic, Box::new(handler));
+        self
+    }
+
+    /// Handles lightningd's `setconfig` calls, made when a dynamic option changes
+    pub fn setconfig<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&RpcParams) -> Result<Value, RpcError> + Send + 'static,
+    {
+        self.handlers.insert(
+            Cow::Borrowed("setconfig"),
+            Handler::Immediate(Box::new(handler)),
+        );
+        self
+    }
+
+    /// Called with the option values and lightningd's configuration, an error fails `init`
+    pub fn on_init<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(Init) -> Result<(), RpcError> + Send + 'static,
+    {
+        self.init = Some(Box::new(handler));
+        self
+    }
+
+    /// whether lightningd may stop and start the plugin while it runs, true by default
+    pub fn dynamic(mut self, dynamic: bool) -> Self {
+        self.manifest.dynamic = dynamic;
+        self
+    }
+
+    pub fn build(self, writer: StdoutWriter) -> Plugin {
+        Plugin {
+            manifest: serde_json::to_value(&self.manifest).unwrap(), // only strings and values
+            handlers: self.handlers,
+            notifications: self.notifications,
+            init: self.init,
+            writer,
+        }
+    }
+}
+
+/// A plugin ready to serve lightningd's requests, see [`Builder`]
+pub struct Plugin {
+    manifest: Value,
+    handlers: HashMap<Cow<'static, str>, Handler>,
+    notifications: HashMap<Cow<'static, str>, NotificationHandler>,
+    init: Option<InitHandler>,
+    writer: StdoutWriter,
+}
+impl Plugin {
+    pub fn manifest(&self) -> &Value {
+        &self.manifest
+    }
+
+    fn handle_init(&self, params: &RpcParams) -> Result<Value, RpcError> {
+        let arg0 = match params {
+            RpcParams::ByPosition(a) => a
+                .first()
+                .ok_or(RpcError {
+                    code: 4.into(),
+                    message: Cow::Borrowed("no arguments supplied"),
+                    data: None,
+                })?
+                .clone(),
+            RpcParams::ByName(a) => serde_json::Value::Object(a.clone()),
+        };
+        let init: Init = serde_json::from_value(arg0)
+            .map_err(|e| format!("{}", e))
+            .with_info(5, "params deserialization error")?;
+        if let Some(handler) = &self.init {
+            handler(init)?;
+        }
+        Ok(serde_json::json!({}))
+    }
+
+    fn handle_notification(&self, method: &str, params: &RpcParams) {
+        if let Some(handler) = self.notifications.get(method) {
+            if let Err(e) = handler(params) {
+                log::error!("RPC EVENT HANDLER ERROR: {}", e);
+            }
+        }
+    }
+
+    /// Dispatches a request or notification to its handler and writes the response, if any
+    pub fn handle(&self, req: RpcReq) {
+        let RpcReq {
+            id, method, params, ..
+        } = req;
+        let responder = match id {
+            Some(id) => Responder {
+                id,
+                writer: self.writer.clone(),
+            },
+            None => return self.handle_notification(&method, &params),
+        };
+        let res = match method.borrow() {
+            "getmanifest" => Ok(self.manifest.clone()),
+            "init" => self.handle_init(&params),
+            method => match self.handlers.get(method) {
+                Some(Handler::Immediate(handler)) => handler(&params),
+                Some(Handler::Deferred(handler)) => match handler(responder.clone(), &params) {
+                    Ok(()) => return,
+                    Err(e) => Err(e),
+                },
+                None => Err(RpcError {
+                    code: 3.into(),
+                    message: Cow::Borrowed("unknown method"),
+                    data: Some(Value::String(method.to_owned())),
+                }),
+            },
+        };
+        if let Err(e) = &res {
+            log::error!("RPC REQUEST HANDLER ERROR: {}", e);
+        }
+        responder.respond(res);
+    }
+
+    /// Handles messages from `input` until it closes
+    pub fn run_from<R: Read>(&self, input: R) {
+        let req_stream: StreamDeserializer<_, RpcReq> =
+            StreamDeserializer::new(serde_json::de::IoRead::new(input));
+        for e_req in req_stream {
+            match e_req {
+                Ok(req) => self.handle(req),
+                Err(e) => {
+                    let res = self.writer.write(&RpcRes {
+                        id: JsonRpcV2Id::Null,
+                        jsonrpc: Default::default(),
+                        result: RpcResult::Error(RpcError {
+                            code: 1.into(),
+                            message: Cow::Borrowed("deserialization error"),
+                            data: Some(Value::String(format!("{}", e))),
+                        }),
+                    });
+                    if let Err(e) = res {
+                        // stdout is gone, so lightningd can't be answered anymore
+                        log::error!("{}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles lightningd's messages on stdin, returning once lightningd has gone away
+    pub fn run(self) {
+        self.run_from(std::io::stdin())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// stdout that lightningd has stopped reading
+    struct Closed;
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stdout_closed() {
+        let writer = StdoutWriter::new(Closed);
+        // after a failed write, every later one fails too
+        writer.write(&Value::Null).unwrap();
+        writer.flush();
+        let plugin = Builder::new().build(writer);
+        plugin.run_from(&b"{not json}\n"[..]);
+    }
+
+    #[test]
+    fn dispatch() {
+        let out = Buffer::default();
+        let writer = StdoutWriter::new(out.clone());
+        let (events, received) = crossbeam_channel::unbounded();
+        let (inits, initialized) = crossbeam_channel::unbounded();
+        let (deferred, deferred_received) = crossbeam_channel::unbounded();
+        let plugin = Builder::new()
+            .option(
+                ConfigOption::new("greeting", OptionType::String, "what to say")
+                    .with_default("hello")
+                    .dynamic(),
+            )
+            .rpcmethod("greet", "[name]", "Say hello", |params| match params {
+                RpcParams::ByPosition(a) => Ok(a.first().cloned().unwrap_or_default()),
+                RpcParams::ByName(_) => Err("by name").with_info(5, "bad params"),
+            })
+            .deferred_rpcmethod("later", "", "Answer later", move |responder, _| {
+                deferred.send(responder).unwrap();
+                Ok(())
+            })
+            .subscribe("block_added", move |params| {
+                events.send(params.clone()).unwrap();
+                Ok(())
+            })
+            .on_init(move |init| {
+                inits.send(init.configuration.rpc_path()).unwrap();
+                Ok(())
+            })
+            .dynamic(false)
+            .build(writer.clone());
+
+        let input = br#"
+            {"jsonrpc":"2.0","id":0,"method":"getmanifest","params":{}}
+            {"jsonrpc":"2.0","id":1,"method":"init","params":{"options":{"greeting":"hi"},"configuration":{"lightning-dir":"/ln","rpc-file":"lightning-rpc"}}}
+            {"jsonrpc":"2.0","method":"block_added","params":{"block_added":{"height":1}}}
+            {"jsonrpc":"2.0","id":2,"method":"greet","params":["world"]}
+            {"jsonrpc":"2.0","id":3,"method":"greet","params":{}}
+            {"jsonrpc":"2.0","id":4,"method":"later","params":[]}
+            {"jsonrpc":"2.0","id":5,"method":"unknown","params":[]}
+        "#;
+        plugin.run_from(&input[..]);
+        deferred_received.recv().unwrap().respond(Ok("done".into()));
+        writer.flush();
+
+        assert_eq!(
+            initialized.try_recv().unwrap(),
+            PathBuf::from("/ln/lightning-rpc")
+        );
+        assert!(received.try_recv().is_ok());
+        let out = out.0.lock().unwrap();
+        let responses: Vec<RpcRes> = serde_json::Deserializer::from_slice(&out)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let results: Vec<(String, Result<Value, RpcError>)> = responses
+            .into_iter()
+            .map(|res| (serde_json::to_string(&res.id).unwrap(), res.result.res()))
+            .collect();
+        let manifest = results[0].1.as_ref().unwrap();
+        assert_eq!(
+            manifest["options"],
+            serde_json::json!([{
+                "name": "greeting",
+                "type": "string",
+                "default": "hello",
+                "dynamic": true,
+                "description": "what to say",
+            }])
+        );
+        assert_eq!(manifest["rpcmethods"][1]["name"], "later");
+        assert_eq!(
+            manifest["subscriptions"],
+            serde_json::json!(["block_added"])
+        );
+        assert_eq!(manifest["dynamic"], false);
+        assert_eq!(results[1].1.as_ref().unwrap(), &serde_json::json!({}));
+        assert_eq!(results[2].1.as_ref().unwrap(), "world");
+        assert_eq!(results[3].1.as_ref().unwrap_err().code, 5.into());
+        assert_eq!(results[4].1.as_ref().unwrap_err().code, 3.into());
+        assert_eq!(results[5].0, "4");
+        assert_eq!(results[5].1.as_ref().unwrap(), "done");
+    }
+}
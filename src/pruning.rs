@@ -1,56 +1,447 @@
-use std::borrow::Cow;
-
+use c_lightning_pruning_plugin::rpc::RpcParams;
 use failure::Error;
 use serde_json::Value;
-use tokio::net::UnixStream;
 
-use crate::rpc::{make_socket_req, JsonRpcV2Id, RpcParams, RpcReq};
+use crate::bitcoin::{
+    get_blockchain_info, make_bitcoin_req, BitcoinClient, BlockchainInfo, PruneMode,
+};
+use crate::client::LightningClient;
+use crate::datastore;
+use crate::status::{unix_now, StatusArc};
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct LightningInfo {
     pub blockheight: u64,
 }
 
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ChannelInfo {
+    pub state: String,
+    #[serde(default)]
+    pub short_channel_id: Option<String>,
+}
+impl ChannelInfo {
+    /// height of the block containing the funding output, if the funding tx has confirmed
+    pub fn funding_height(&self) -> Option<u64> {
+        self.short_channel_id
+            .as_ref()?
+            .split(['x', ':']) // older versions of c-lightning use ':'
+            .next()?
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ChannelList {
+    #[serde(default)]
+    pub channels: Vec<ChannelInfo>,
+}
+
+async fn lightning_req(
+    client: &LightningClient,
+    method: &'static str,
+) -> Result<Result<Value, c_lightning_pruning_plugin::rpc::RpcError>, Error> {
+    client.call(method, RpcParams::ByPosition(Vec::new())).await
+}
+
+/// Returns the lowest block height that lightningd may still need to rescan on behalf of a
+/// channel, or `None` if no channel has a confirmed funding output.
+///
+/// Every channel lightningd still tracks (open, closing or resolving onchain) is anchored by
+/// its funding output, and its close tx and any outputs still being resolved can only be
+/// mined at or after that height, so the funding height bounds all of them.
+pub async fn channel_floor(client: &LightningClient) -> Result<Option<u64>, Error> {
+    let res = match lightning_req(client, "listpeerchannels").await? {
+        Ok(res) => res,
+        Err(e) => {
+            // `listpeerchannels` was added in v23.02, fall back to `listfunds` on older nodes
+            log::debug!("listpeerchannels failed, falling back to listfunds: {}", e);
+            lightning_req(client, "listfunds").await??
+        }
+    };
+    let res: ChannelList = serde_json::from_value(res)?;
+    Ok(res
+        .channels
+        .iter()
+        .filter_map(|c| {
+            let height = c.funding_height();
+            if height.is_none() {
+                log::debug!("channel in state {} has no confirmed funding", c.state);
+            }
+            height
+        })
+        .min())
+}
+
+/// Returns the lowest block lightningd may rescan from at `blockheight`, given its `rescan`
+/// option: that many blocks below its height, or the absolute height `-rescan` if negative.
+///
+/// An absolute height above `blockheight` is treated as `blockheight`.
+pub fn rescan_from(blockheight: u64, rescan: i64) -> u64 {
+    if rescan < 0 {
+        rescan.unsigned_abs().min(blockheight)
+    } else {
+        blockheight.saturating_sub(rescan as u64)
+    }
+}
+
+/// Returns the range of pruned blocks lightningd may rescan, if any
+pub async fn rescan_gap(
+    client: &LightningClient,
+    bitcoin_info: &BlockchainInfo,
+    rescan: i64,
+) -> Result<Option<(u64, u64)>, Error> {
+    let pruneheight = match bitcoin_info.pruneheight {
+        Some(height) if bitcoin_info.pruned && height > 0 => height,
+        _ => return Ok(None),
+    };
+    let res = lightning_req(client, "getinfo").await??;
+    let res: LightningInfo = serde_json::from_value(res)?;
+    let from = rescan_from(res.blockheight, rescan);
+    Ok(if from < pruneheight {
+        Some((from, pruneheight - 1))
+    } else {
+        None
+    })
+}
+
+/// Requests sent from the RPC handler thread to the pruning loop
+#[derive(Debug)]
+pub enum PruningCommand {
+    /// run a pruning check immediately and send back the outcome, optionally overriding
+    /// `pruning-dry-run`
+    PruneNow {
+        dry_run: Option<bool>,
+        reply: crate::handlers::Reply<PruneReport>,
+    },
+    /// lightningd has processed a new block at this height
+    BlockAdded(u64),
+    /// an option was changed with `setconfig`
+    SetConfig(crate::handlers::ConfigUpdate),
+    /// hold off pruning, for `duration` seconds if given
+    Pause {
+        duration: Option<u64>,
+        reply: crate::handlers::Reply<PauseReport>,
+    },
+    Resume {
+        reply: crate::handlers::Reply<PauseReport>,
+    },
+    /// download pruned blocks from `from` to `to` inclusive again
+    Refetch {
+        from: u64,
+        to: u64,
+        reply: crate::handlers::Reply<crate::refetch::RefetchProgress>,
+    },
+    /// read a page of prune history from the datastore
+    History {
+        offset: usize,
+        limit: usize,
+        reply: crate::handlers::Reply<crate::history::HistoryPage>,
+    },
+}
+
+impl PruningCommand {
+    /// Answers the command with `error`, if it expects an answer
+    pub fn reject(self, error: String) {
+        match self {
+            PruningCommand::PruneNow { reply, .. } => reply.send(Err(error)),
+            PruningCommand::Pause { reply, .. } | PruningCommand::Resume { reply } => {
+                reply.send(Err(error))
+            }
+            PruningCommand::Refetch { reply, .. } => reply.send(Err(error)),
+            PruningCommand::History { reply, .. } => reply.send(Err(error)),
+            PruningCommand::BlockAdded(_) | PruningCommand::SetConfig(_) => (),
+        }
+    }
+}
+
+/// Set by `pruning-pause`, and kept in lightningd's datastore so a restart doesn't resume pruning
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct PauseState {
+    /// unix timestamp of when pruning was paused
+    pub since: u64,
+    /// unix timestamp of when pruning resumes on its own, if ever
+    pub until: Option<u64>,
+}
+
+const PAUSE_KEY: &[&str] = &["paused"];
+
+/// Outcome of `pruning-pause` or `pruning-resume`
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct PauseReport {
+    pub paused: Option<PauseState>,
+    /// why the new state couldn't be saved, in which case it only lasts until a restart
+    pub persist_error: Option<String>,
+}
+
+pub async fn load_pause(client: &LightningClient) -> Result<Option<PauseState>, Error> {
+    Ok(match datastore::get(client, PAUSE_KEY).await? {
+        Some(s) => Some(serde_json::from_str(&s)?),
+        None => None,
+    })
+}
+
+/// Pauses or resumes pruning, saving the new state to lightningd's datastore
+pub async fn set_pause(
+    client: &LightningClient,
+    status: &StatusArc,
+    paused: &mut Option<PauseState>,
+    new: Option<PauseState>,
+) -> PauseReport {
+    match &new {
+        Some(PauseState {
+            until: Some(until), ..
+        }) => log::info!("pruning paused for {}s", until.saturating_sub(unix_now())),
+        Some(_) => log::info!("pruning paused until pruning-resume is called"),
+        None if paused.is_some() => log::info!("pruning resumed"),
+        None => (),
+    }
+    let res = match &new {
+        Some(state) => match serde_json::to_string(state) {
+            Ok(s) => datastore::set(client, PAUSE_KEY, &s).await,
+            Err(e) => Err(e.into()),
+        },
+        None => datastore::del(client, PAUSE_KEY).await,
+    };
+    let persist_error = res.err().map(|e| {
+        log::warn!(
+            "unable to save pause state, it will be lost on restart: {}",
+            e
+        );
+        status.count_error("datastore");
+        format!("{}", e)
+    });
+    *paused = new;
+    status.update(|s| s.paused = paused.clone());
+    PauseReport {
+        paused: paused.clone(),
+        persist_error,
+    }
+}
+
+/// Parameters that decide how far to prune
+#[derive(Clone, Debug)]
+pub struct PruningPolicy {
+    /// lightningd's `rescan`: number of blocks below its height that it may need to rescan, or
+    /// the absolute height to rescan from if negative
+    pub rescan: i64,
+    /// compute the prune height but skip `pruneblockchain`
+    pub dry_run: bool,
+    /// defer pruning while bitcoind's `verificationprogress` is below this
+    pub min_verification_progress: f64,
+    /// defer pruning while lightningd and bitcoind disagree on the tip by more than this
+    pub max_sync_gap: u64,
+    /// report an anomaly when lightningd and bitcoind disagree on the tip by more than this
+    pub anomaly_gap: u64,
+    /// only prune as far as needed to keep bitcoind's block files under this many MiB,
+    /// 0 to always prune as far as is safe
+    pub target_mb: u64,
+}
+
+/// Logs what the plugin can do given bitcoind's prune mode
+pub fn log_prune_mode(info: &BlockchainInfo) {
+    match info.prune_mode() {
+        PruneMode::Manual => log::info!("bitcoind is in manual prune mode"),
+        PruneMode::Automatic => log::warn!(
+            "bitcoind is pruning automatically to {} MiB and may prune blocks lightningd still needs, restart it with -prune=1",
+            info.prune_target_size.unwrap_or_default() / 1024 / 1024
+        ),
+        PruneMode::Disabled => log::warn!(
+            "bitcoind is not running with -prune=1, pruning is disabled until it is"
+        ),
+    }
+}
+
+/// Outcome of a single pruning check
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct PruneReport {
+    pub blockheight: u64,
+    pub dry_run: bool,
+    pub prune_mode: Option<PruneMode>,
+    /// why no prune height was computed, if one wasn't
+    pub skipped: Option<String>,
+    /// unexpected disagreement between lightningd and bitcoind
+    pub anomaly: Option<String>,
+    /// height requested from `pruneblockchain`, if pruning was attempted
+    pub prune_height: Option<u64>,
+    /// `pruneheight` reported by bitcoind after pruning
+    pub bitcoind_pruneheight: Option<u64>,
+    /// `size_on_disk` reported by bitcoind before pruning
+    pub size_on_disk: Option<u64>,
+    /// height of the last block `pruneblockchain` pruned, which bitcoind rounds down to a block
+    /// file boundary
+    pub pruned_height: Option<u64>,
+}
+
 pub async fn prune(
-    socket: &mut UnixStream,
-    bitcoin_req: &reqwest::RequestBuilder,
-    rescan: u64,
-) -> Result<(), Error> {
+    client: &LightningClient,
+    bitcoin: &BitcoinClient,
+    policy: &PruningPolicy,
+) -> Result<PruneReport, Error> {
     // fetch scanned block height from c-lightning
-    let res = make_socket_req(
-        socket,
-        RpcReq {
-            id: Some(JsonRpcV2Id::Num(0.into())),
-            jsonrpc: Default::default(),
-            method: Cow::Borrowed("getinfo"),
-            params: RpcParams::ByPosition(Vec::new()),
-        },
-    )
-    .await?
-    .result
-    .res()?;
+    let res = lightning_req(client, "getinfo").await??;
     let res: LightningInfo = serde_json::from_value(res)?;
-    if res.blockheight < rescan + 1 {
-        return Ok(()); // don't want to prune to negative height
+    let bitcoin_info = get_blockchain_info(bitcoin).await?;
+    let mut report = PruneReport {
+        blockheight: res.blockheight,
+        dry_run: policy.dry_run,
+        prune_mode: Some(bitcoin_info.prune_mode()),
+        bitcoind_pruneheight: bitcoin_info.pruneheight,
+        size_on_disk: bitcoin_info.size_on_disk,
+        ..Default::default()
+    };
+    if bitcoin_info.prune_mode() == PruneMode::Disabled {
+        report.skipped = Some("bitcoind is not in prune mode".to_owned());
+        return Ok(report);
+    }
+    // don't prune while either side is still syncing
+    if bitcoin_info.initialblockdownload {
+        report.skipped = Some("bitcoind is in initial block download".to_owned());
+        return Ok(report);
+    }
+    if let Some(progress) = bitcoin_info.verificationprogress {
+        if progress < policy.min_verification_progress {
+            report.skipped = Some(format!(
+                "bitcoind verification progress {} is below {}",
+                progress, policy.min_verification_progress
+            ));
+            return Ok(report);
+        }
+    }
+    let gap = bitcoin_info.blocks as i64 - res.blockheight as i64;
+    if gap.unsigned_abs() > policy.anomaly_gap {
+        report.anomaly = Some(format!(
+            "lightningd is at block {} but bitcoind is at block {}",
+            res.blockheight, bitcoin_info.blocks
+        ));
+    }
+    if gap > policy.max_sync_gap as i64 {
+        report.skipped = Some(format!(
+            "lightningd is catching up: {} blocks behind bitcoind",
+            gap
+        ));
+        return Ok(report);
+    }
+    if -gap > policy.max_sync_gap as i64 {
+        report.skipped = Some(format!(
+            "bitcoind is catching up: {} blocks behind lightningd",
+            -gap
+        ));
+        return Ok(report);
+    }
+    let rescan_from = rescan_from(res.blockheight, policy.rescan);
+    if rescan_from == 0 {
+        report.skipped = Some("not enough blocks to prune".to_owned());
+        return Ok(report); // don't want to prune to negative height
+    }
+    let mut prune_height = rescan_from - 1;
+    // never prune blocks that an open, closing or onchain channel may still need
+    if let Some(floor) = channel_floor(client).await? {
+        if floor == 0 {
+            report.skipped = Some("channel funded in genesis block".to_owned());
+            return Ok(report); // don't want to prune to negative height
+        }
+        if floor - 1 < prune_height {
+            log::info!(
+                "clamping prune height from {} to {} to preserve channel at block {}",
+                prune_height,
+                floor - 1,
+                floor
+            );
+            prune_height = floor - 1;
+        }
+    }
+    // with a disk usage target, prune only as far as needed, never past the safe height above
+    if policy.target_mb > 0 {
+        let target_size = policy.target_mb.saturating_mul(1024 * 1024);
+        match bitcoin_info.target_height(target_size) {
+            None => {
+                report.skipped = Some(format!(
+                    "block files are within the {} MiB target",
+                    policy.target_mb
+                ));
+                return Ok(report);
+            }
+            Some(target_height) if target_height < prune_height => {
+                log::info!(
+                    "pruning to {} instead of {} is enough to meet the {} MiB target",
+                    target_height,
+                    prune_height,
+                    policy.target_mb
+                );
+                prune_height = target_height;
+            }
+            Some(target_height) => log::warn!(
+                "meeting the {} MiB target would require pruning to {}, past the safe height {}",
+                policy.target_mb,
+                target_height,
+                prune_height
+            ),
+        }
+    }
+    // bitcoind only removes whole block files, so it may already be past the height we'd ask for
+    if let Some(pruneheight) = bitcoin_info.pruneheight {
+        if pruneheight > prune_height {
+            report.skipped = Some(format!(
+                "bitcoind has already pruned to {}, past {}",
+                pruneheight - 1,
+                prune_height
+            ));
+            return Ok(report);
+        }
+    }
+    report.prune_height = Some(prune_height);
+    if policy.dry_run {
+        log::info!("dry run: would prune bitcoin to {}", prune_height);
+        return Ok(report);
     }
-    let prune_height = res.blockheight - rescan - 1;
     log::info!("pruning bitcoin to {}", prune_height);
     // run "pruneblockchain" against bitcoind
-    let res = bitcoin_req
-        .try_clone()
-        .ok_or_else(|| failure::format_err!("cannot clone request"))?
-        .json(&RpcReq {
-            id: Some(JsonRpcV2Id::Num(0.into())),
-            jsonrpc: Default::default(),
-            method: Cow::Borrowed("pruneblockchain"),
-            params: RpcParams::ByPosition(vec![Value::Number(prune_height.into())]),
-        })
-        .send()
-        .await?;
-    match res.error_for_status_ref() {
-        Ok(_) => (),
-        Err(e) => return Err(failure::format_err!("{}: {:?}", e, res.text().await)),
+    let res = make_bitcoin_req(
+        bitcoin,
+        "pruneblockchain",
+        vec![Value::Number(prune_height.into())],
+    )
+    .await?;
+    let pruned_height = res
+        .as_i64()
+        .ok_or_else(|| failure::format_err!("unexpected pruneblockchain result: {}", res))?;
+    // -1 when every block up to `prune_height` is still in a block file that can't be removed
+    let pruned_height = if pruned_height < 0 {
+        None
+    } else {
+        Some(pruned_height as u64)
+    };
+    report.pruned_height = pruned_height;
+    match pruned_height {
+        Some(pruned_height) if pruned_height > prune_height => {
+            report.anomaly = Some(format!(
+                "bitcoind pruned to {}, past the requested {}",
+                pruned_height, prune_height
+            ));
+        }
+        Some(pruned_height) if pruned_height < prune_height => log::info!(
+            "bitcoind pruned to {}, the last block file boundary before {}",
+            pruned_height,
+            prune_height
+        ),
+        _ => (),
+    }
+    let pruneheight = get_blockchain_info(bitcoin).await?.pruneheight;
+    if let Some(pruned_height) = pruned_height {
+        if pruneheight.is_some() && pruneheight != Some(pruned_height + 1) {
+            log::debug!(
+                "bitcoind reports pruneheight {:?} after pruning to {}",
+                pruneheight,
+                pruned_height
+            );
+        }
+    }
+    if pruned_height.is_none() || pruneheight == bitcoin_info.pruneheight {
+        log::info!("no block files could be removed below {}", prune_height);
     }
+    report.bitcoind_pruneheight = pruneheight;
 
-    Ok(())
+    Ok(report)
 }
@@ -0,0 +1,185 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use c_lightning_pruning_plugin::rpc::RpcError;
+use failure::Error;
+use serde_json::Value;
+
+use crate::bitcoin::{make_bitcoin_req, BitcoinClient};
+use crate::status::StatusArc;
+
+/// how often to check whether a requested block has arrived
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// how long to wait for a peer to deliver a block before asking the next
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Progress of a `pruning-refetch`, reported by `pruning-status`
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct RefetchProgress {
+    pub from: u64,
+    pub to: u64,
+    /// height being fetched
+    pub current: Option<u64>,
+    /// blocks downloaded from peers so far
+    pub fetched: u64,
+    /// blocks bitcoind still had
+    pub available: u64,
+    pub running: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+struct PeerInfo {
+    id: u64,
+    #[serde(default)]
+    servicesnames: Vec<String>,
+}
+
+/// Returns the ids of the peers to ask for old blocks, full nodes first
+pub async fn peers(bitcoin: &BitcoinClient) -> Result<Vec<u64>, Error> {
+    let peers: Vec<PeerInfo> =
+        serde_json::from_value(make_bitcoin_req(bitcoin, "getpeerinfo", Vec::new()).await?)?;
+    // NETWORK_LIMITED peers only serve recent blocks
+    let (mut full, limited): (Vec<_>, Vec<_>) = peers
+        .into_iter()
+        .partition(|p| p.servicesnames.iter().any(|s| s == "NETWORK"));
+    full.extend(limited);
+    if full.is_empty() {
+        failure::bail!("bitcoind has no peers to fetch blocks from");
+    }
+    Ok(full.into_iter().map(|p| p.id).collect())
+}
+
+fn already_downloaded(e: &Error) -> bool {
+    e.downcast_ref::<RpcError>()
+        .is_some_and(|e| e.message.contains("already downloaded"))
+}
+
+/// Returns whether `e` is bitcoind refusing to serve a block it has pruned
+pub fn is_pruned(e: &Error) -> bool {
+    e.downcast_ref::<RpcError>()
+        .is_some_and(|e| e.message.contains("pruned data"))
+}
+
+/// Returns whether bitcoind has the block's data
+async fn has_block(bitcoin: &BitcoinClient, hash: &Value) -> Result<bool, Error> {
+    match make_bitcoin_req(bitcoin, "getblock", vec![hash.clone(), 0.into()]).await {
+        Ok(_) => Ok(true),
+        Err(e) if is_pruned(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Makes sure bitcoind has the block at `height`, returning whether it had to be downloaded
+pub async fn fetch_block(
+    bitcoin: &BitcoinClient,
+    height: u64,
+    peers: &[u64],
+) -> Result<bool, Error> {
+    let hash = make_bitcoin_req(bitcoin, "getblockhash", vec![height.into()]).await?;
+    if has_block(bitcoin, &hash).await? {
+        return Ok(false);
+    }
+    // `getblockfrompeer` needs the header, which bitcoind keeps for pruned blocks
+    make_bitcoin_req(bitcoin, "getblockheader", vec![hash.clone()]).await?;
+    for peer in peers {
+        // only ask each peer once: bitcoind either rejects a repeat request or sends another
+        let params = vec![hash.clone(), Value::from(*peer)];
+        match make_bitcoin_req(bitcoin, "getblockfrompeer", params).await {
+            Ok(_) => (),
+            Err(e) if already_downloaded(&e) => return Ok(true),
+            Err(e) => {
+                log::debug!("unable to fetch block {} from peer {}: {}", height, peer, e);
+                continue;
+            }
+        }
+        let deadline = Instant::now() + PEER_TIMEOUT;
+        loop {
+            if has_block(bitcoin, &hash).await? {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                log::debug!("peer {} did not provide block {} in time", peer, height);
+                break;
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+    Err(failure::format_err!("no peer provided block {}", height))
+}
+
+async fn refetch(
+    bitcoin: &BitcoinClient,
+    status: &StatusArc,
+    from: u64,
+    to: u64,
+) -> Result<(), Error> {
+    let peers = peers(bitcoin).await?;
+    for height in from..=to {
+        status.update(|s| {
+            if let Some(r) = &mut s.refetch {
+                r.current = Some(height);
+            }
+        });
+        let fetched = fetch_block(bitcoin, height, &peers).await?;
+        if fetched {
+            log::info!(
+                "refetched block {} ({} of {})",
+                height,
+                height - from + 1,
+                to - from + 1
+            );
+        }
+        status.update(|s| {
+            if let Some(r) = &mut s.refetch {
+                if fetched {
+                    r.fetched += 1;
+                } else {
+                    r.available += 1;
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Starts downloading any pruned blocks from `from` to `to` inclusive in the background
+pub fn spawn(
+    bitcoin: Arc<BitcoinClient>,
+    status: StatusArc,
+    from: u64,
+    to: u64,
+) -> Result<RefetchProgress, String> {
+    if from > to {
+        return Err(format!("invalid range {} to {}", from, to));
+    }
+    if status.get().refetch.is_some_and(|r| r.running) {
+        return Err("a refetch is already in progress".to_owned());
+    }
+    let progress = RefetchProgress {
+        from,
+        to,
+        running: true,
+        ..Default::default()
+    };
+    status.update(|s| s.refetch = Some(progress.clone()));
+    log::info!("refetching blocks {} to {}", from, to);
+    tokio::spawn(async move {
+        let res = refetch(&bitcoin, &status, from, to).await;
+        match &res {
+            Ok(()) => log::info!("blocks {} to {} are available", from, to),
+            Err(e) => log::error!("refetching blocks {} to {} failed: {}", from, to, e),
+        }
+        status.update(|s| {
+            if let Some(r) = &mut s.refetch {
+                r.running = false;
+                r.current = None;
+                r.error = res.err().map(|e| format!("{}", e));
+                if r.error.is_some() {
+                    s.count_error("refetch");
+                }
+            }
+        });
+    });
+    Ok(progress)
+}
@@ -69,7 +69,7 @@ pub struct RpcReq {
 }
 impl AsRef<RpcReq> for RpcReq {
     fn as_ref(&self) -> &RpcReq {
-        &self
+        self
     }
 }
 
@@ -165,22 +165,3 @@ impl std::fmt::Display for RpcError {
     }
 }
 impl std::error::Error for RpcError {}
-
-pub async fn make_socket_req(
-    socket: &mut tokio::net::UnixStream,
-    req: RpcReq,
-) -> Result<RpcRes, failure::Error> {
-    use tokio::io::AsyncWriteExt;
-    use tokio::stream::StreamExt;
-
-    socket.write_all(&serde_json::to_vec(&req)?).await?;
-    let res = crate::async_io::RpcResponseStream::new(socket)
-        .next()
-        .await
-        .ok_or_else(|| {
-            tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "socket closed")
-        })??;
-    let res = serde_json::from_slice(&res)?;
-
-    Ok(res)
-}
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bitcoin::PruneMode;
+use crate::client::ConnectionState;
+use crate::pruning::PauseState;
+use crate::refetch::RefetchProgress;
+
+/// Snapshot of what the pruning loop has been doing, reported by the `pruning-status` method
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct PruningStatus {
+    /// unix timestamp of the last completed pruning check
+    pub last_run: Option<u64>,
+    /// unix timestamp of the last successful `pruneblockchain`
+    pub last_prune: Option<u64>,
+    /// height most recently requested from `pruneblockchain`
+    pub prune_height: Option<u64>,
+    /// height bitcoind reported pruning to in response to the last `pruneblockchain`
+    pub pruned_height: Option<u64>,
+    /// `pruneheight` as reported by bitcoind's `getblockchaininfo`
+    pub bitcoind_pruneheight: Option<u64>,
+    pub bitcoind_prune_mode: Option<PruneMode>,
+    /// `size_on_disk` as reported by bitcoind's `getblockchaininfo`
+    pub size_on_disk: Option<u64>,
+    /// why the last pruning check didn't prune, if it didn't
+    pub skipped: Option<String>,
+    /// unexpected disagreement between lightningd and bitcoind seen in the last pruning check
+    pub anomaly: Option<String>,
+    /// lightningd's block height at the last pruning check
+    pub blockheight: Option<u64>,
+    pub rescan: Option<i64>,
+    pub dry_run: bool,
+    /// unix timestamp of the next scheduled pruning check
+    pub next_run: Option<u64>,
+    pub last_error: Option<String>,
+    pub lightningd_connection: Option<ConnectionState>,
+    /// set while pruning is held off by `pruning-pause`
+    pub paused: Option<PauseState>,
+    /// the current or last `pruning-refetch`
+    pub refetch: Option<RefetchProgress>,
+    /// number of errors so far, by where they occurred
+    pub errors: BTreeMap<String, u64>,
+}
+impl PruningStatus {
+    pub fn count_error(&mut self, category: &str) {
+        *self.errors.entry(category.to_owned()).or_default() += 1;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StatusArc {
+    state: Arc<RwLock<PruningStatus>>,
+}
+impl StatusArc {
+    pub fn get(&self) -> PruningStatus {
+        self.state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+    pub fn update<F: FnOnce(&mut PruningStatus)>(&self, f: F) {
+        f(&mut self.state.write().unwrap_or_else(PoisonError::into_inner))
+    }
+    pub fn count_error(&self, category: &str) {
+        self.update(|s| s.count_error(category))
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,525 @@
+//! Stand-ins for lightningd and bitcoind, and a handle to drive the plugin binary the way
+//! lightningd does
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use serde_json::{json, Value};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the mock bitcoind reports, and the `pruneblockchain` calls it has received
+#[derive(Debug)]
+pub struct BitcoindState {
+    pub blocks: u64,
+    pub pruneheight: u64,
+    pub size_on_disk: u64,
+    pub initialblockdownload: bool,
+    /// hold `pruneblockchain` calls unanswered while set
+    pub stall_prune: bool,
+    /// answer `pruneblockchain` with -1 and prune nothing, as when no block file can be removed
+    pub unprunable: bool,
+    /// answer 401 to requests without this `Authorization` header, if set
+    pub authorization: Option<String>,
+    pub prune_calls: Vec<u64>,
+    /// pruned blocks downloaded again with `getblockfrompeer`
+    pub refetched: Vec<u64>,
+    /// `getblockfrompeer` calls received, as (height, peer id)
+    pub fetch_requests: Vec<(u64, u64)>,
+}
+impl Default for BitcoindState {
+    fn default() -> Self {
+        BitcoindState {
+            blocks: 1000,
+            pruneheight: 0,
+            size_on_disk: 1_000_000_000,
+            initialblockdownload: false,
+            stall_prune: false,
+            unprunable: false,
+            authorization: None,
+            prune_calls: Vec::new(),
+            refetched: Vec::new(),
+            fetch_requests: Vec::new(),
+        }
+    }
+}
+
+/// An http server answering bitcoind's JSON-RPC methods from a [`BitcoindState`]
+pub struct MockBitcoind {
+    pub addr: SocketAddr,
+    pub state: Arc<Mutex<BitcoindState>>,
+}
+impl MockBitcoind {
+    pub fn spawn() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(BitcoindState::default()));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = thread_state.clone();
+                match stream {
+                    Ok(stream) => std::thread::spawn(move || serve_http(stream, &state)),
+                    Err(_) => break,
+                };
+            }
+        });
+        MockBitcoind { addr, state }
+    }
+
+    pub fn state(&self) -> std::sync::MutexGuard<'_, BitcoindState> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// the mock's block hashes are made up from their heights
+fn block_height(hash: &Value) -> u64 {
+    hash.as_str()
+        .unwrap()
+        .trim_start_matches("block")
+        .parse()
+        .unwrap()
+}
+
+fn bitcoind_result(
+    state: &Mutex<BitcoindState>,
+    method: &str,
+    params: &Value,
+) -> Result<Value, Value> {
+    let mut state = state.lock().unwrap();
+    Ok(match method {
+        "getblockchaininfo" => json!({
+            "chain": "regtest",
+            "blocks": state.blocks,
+            "headers": state.blocks,
+            "initialblockdownload": state.initialblockdownload,
+            "verificationprogress": 1.0,
+            "pruned": true,
+            "pruneheight": state.pruneheight,
+            "size_on_disk": state.size_on_disk,
+            "automatic_pruning": false,
+        }),
+        "pruneblockchain" => {
+            let height = params[0].as_u64().unwrap();
+            state.prune_calls.push(height);
+            if state.unprunable {
+                return Ok(json!(-1));
+            }
+            state.pruneheight = height + 1;
+            json!(height)
+        }
+        "getpeerinfo" => json!([{ "id": 7, "servicesnames": ["NETWORK", "WITNESS"] }]),
+        "getblockhash" => json!(format!("block{}", params[0].as_u64().unwrap())),
+        "getblockheader" => json!({ "hash": params[0] }),
+        "getblock" => {
+            let height = block_height(&params[0]);
+            if height < state.pruneheight && !state.refetched.contains(&height) {
+                return Err(json!({ "code": -1, "message": "Block not available (pruned data)" }));
+            }
+            json!("00")
+        }
+        "getblockfrompeer" => {
+            let height = block_height(&params[0]);
+            state
+                .fetch_requests
+                .push((height, params[1].as_u64().unwrap()));
+            // the block arrives right away
+            state.refetched.push(height);
+            json!({})
+        }
+        _ => Value::Null,
+    })
+}
+
+fn serve_http(stream: TcpStream, state: &Mutex<BitcoindState>) {
+    let mut reader = BufReader::new(stream);
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut split = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (split.next(), split.next()) {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_owned());
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    let mut stream = reader.into_inner();
+    let expected = state.lock().unwrap().authorization.clone();
+    if expected.is_some() && authorization != expected {
+        stream
+            .write_all(
+                b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        return;
+    }
+    let req: Value = serde_json::from_slice(&body).unwrap();
+    let method = req["method"].as_str().unwrap();
+    while method == "pruneblockchain" && state.lock().unwrap().stall_prune {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let (result, error) = match bitcoind_result(state, method, &req["params"]) {
+        Ok(result) => (result, Value::Null),
+        Err(error) => (Value::Null, error),
+    };
+    let res = serde_json::to_vec(&json!({
+        "result": result,
+        "error": error,
+        "id": req["id"],
+    }))
+    .unwrap();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        res.len()
+    )
+    .unwrap();
+    stream.write_all(&res).unwrap();
+}
+
+/// What the mock lightningd reports
+#[derive(Debug, Default)]
+pub struct LightningdState {
+    pub blockheight: u64,
+    pub rescan: i64,
+    /// answer `listconfigs` with an error
+    pub listconfigs_error: bool,
+    /// have the plugin authenticate to bitcoind with the cookie in this datadir
+    pub bitcoin_datadir: Option<PathBuf>,
+    pub short_channel_ids: Vec<String>,
+    pub datastore: BTreeMap<Vec<String>, String>,
+}
+
+/// A lightningd RPC socket answering from a [`LightningdState`]
+pub struct MockLightningd {
+    pub dir: PathBuf,
+    pub bitcoind: SocketAddr,
+    pub state: Arc<Mutex<LightningdState>>,
+}
+impl MockLightningd {
+    pub fn spawn(name: &str, bitcoind: SocketAddr) -> Self {
+        let dir = std::env::temp_dir().join(format!("pruning-e2e-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let listener = UnixListener::bind(dir.join("lightning-rpc")).unwrap();
+        let state = Arc::new(Mutex::new(LightningdState {
+            blockheight: 1000,
+            rescan: 15,
+            ..Default::default()
+        }));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = thread_state.clone();
+                match stream {
+                    Ok(stream) => std::thread::spawn(move || serve_rpc(stream, &state, bitcoind)),
+                    Err(_) => break,
+                };
+            }
+        });
+        MockLightningd {
+            dir,
+            bitcoind,
+            state,
+        }
+    }
+
+    pub fn state(&self) -> std::sync::MutexGuard<'_, LightningdState> {
+        self.state.lock().unwrap()
+    }
+}
+impl Drop for MockLightningd {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// how `bcli`, or the plugin when it is the backend, is told to reach bitcoind
+fn bitcoin_options(bitcoind: SocketAddr, datadir: Option<&PathBuf>) -> Value {
+    let mut options = json!({
+        "bitcoin-rpcconnect": bitcoind.ip().to_string(),
+        "bitcoin-rpcport": bitcoind.port(),
+    });
+    match datadir {
+        Some(datadir) => options["bitcoin-datadir"] = json!(datadir),
+        None => {
+            options["bitcoin-rpcuser"] = json!("user");
+            options["bitcoin-rpcpassword"] = json!("password");
+        }
+    }
+    options
+}
+
+fn datastore_key(params: &Value) -> Vec<String> {
+    serde_json::from_value(params["key"].clone()).unwrap()
+}
+
+fn lightningd_result(
+    state: &Mutex<LightningdState>,
+    bitcoind: SocketAddr,
+    method: &str,
+    params: &Value,
+) -> Value {
+    let mut state = state.lock().unwrap();
+    match method {
+        "listconfigs" => json!({
+            "network": "regtest",
+            "always-use-proxy": false,
+            "rescan": state.rescan,
+            "plugins": [{
+                "path": "/usr/libexec/c-lightning/plugins/bcli",
+                "name": "bcli",
+                "options": bitcoin_options(bitcoind, state.bitcoin_datadir.as_ref()),
+            }],
+        }),
+        "getinfo" => json!({ "blockheight": state.blockheight }),
+        "listpeerchannels" => json!({
+            "channels": state
+                .short_channel_ids
+                .iter()
+                .map(|scid| json!({ "state": "CHANNELD_NORMAL", "short_channel_id": scid }))
+                .collect::<Vec<_>>(),
+        }),
+        "listdatastore" => {
+            let key = datastore_key(params);
+            json!({
+                "datastore": state
+                    .datastore
+                    .iter()
+                    .filter(|(k, _)| k.starts_with(&key))
+                    .map(|(k, v)| json!({ "key": k, "string": v }))
+                    .collect::<Vec<_>>(),
+            })
+        }
+        "datastore" => {
+            let value = params["string"].as_str().unwrap().to_owned();
+            state.datastore.insert(datastore_key(params), value);
+            json!({})
+        }
+        "deldatastore" => {
+            state.datastore.remove(&datastore_key(params));
+            json!({})
+        }
+        _ => Value::Null,
+    }
+}
+
+fn serve_rpc(stream: UnixStream, state: &Mutex<LightningdState>, bitcoind: SocketAddr) {
+    let mut writer = stream.try_clone().unwrap();
+    let reqs = serde_json::Deserializer::from_reader(stream).into_iter::<Value>();
+    for req in reqs {
+        let req = match req {
+            Ok(req) => req,
+            Err(_) => return,
+        };
+        let method = req["method"].as_str().unwrap();
+        let res = if method == "listconfigs" && state.lock().unwrap().listconfigs_error {
+            json!({
+                "jsonrpc": "2.0",
+                "id": req["id"],
+                "error": { "code": -32601, "message": "listconfigs unavailable" },
+            })
+        } else {
+            json!({
+                "jsonrpc": "2.0",
+                "id": req["id"],
+                "result": lightningd_result(state, bitcoind, method, &req["params"]),
+            })
+        };
+        let mut res = serde_json::to_vec(&res).unwrap();
+        res.extend_from_slice(b"\n\n");
+        if writer.write_all(&res).is_err() {
+            return;
+        }
+    }
+}
+
+/// The plugin binary, driven over its stdin and stdout
+pub struct Plugin {
+    child: Child,
+    stdin: ChildStdin,
+    /// everything the plugin writes that isn't a `log` notification
+    messages: Receiver<Value>,
+    /// responses that arrived while waiting for another
+    responses: HashMap<u64, Value>,
+    next_id: u64,
+}
+impl Plugin {
+    pub fn spawn() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_c-lightning-pruning-plugin"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (sender, messages) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            for msg in serde_json::Deserializer::from_reader(stdout).into_iter::<Value>() {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                };
+                if msg["method"] == "log" {
+                    continue;
+                }
+                if sender.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+        Plugin {
+            child,
+            stdin,
+            messages,
+            responses: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Sends `getmanifest` and `init` as lightningd does on startup
+    pub fn start(lightningd: &MockLightningd, mut options: Value) -> Self {
+        if cfg!(feature = "backend") {
+            let datadir = lightningd.state().bitcoin_datadir.clone();
+            let bitcoin_options = bitcoin_options(lightningd.bitcoind, datadir.as_ref());
+            for (name, val) in bitcoin_options.as_object().unwrap() {
+                options[name] = val.clone();
+            }
+        }
+        let mut plugin = Plugin::spawn();
+        plugin.call("getmanifest", json!({})).unwrap();
+        plugin
+            .call(
+                "init",
+                json!({
+                    "options": options,
+                    "configuration": {
+                        "lightning-dir": lightningd.dir,
+                        "rpc-file": "lightning-rpc",
+                        "startup": true,
+                        "network": "regtest",
+                    },
+                }),
+            )
+            .unwrap();
+        plugin
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        let msg = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        self.send(&msg);
+    }
+
+    fn send(&mut self, msg: &Value) {
+        let mut msg = serde_json::to_vec(msg).unwrap();
+        msg.extend_from_slice(b"\n\n");
+        self.stdin.write_all(&msg).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    /// Sends a request for `method` without waiting for the response
+    pub fn request(&mut self, method: &str, params: Value) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let req = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        self.send(&req);
+        id
+    }
+
+    /// Waits for the response to request `id`, returning its result or error
+    pub fn response(&mut self, id: u64) -> Result<Value, Value> {
+        let deadline = Instant::now() + TIMEOUT;
+        let msg = loop {
+            if let Some(msg) = self.responses.remove(&id) {
+                break msg;
+            }
+            let msg = match self
+                .messages
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => panic!("no response to request {}", id),
+                Err(RecvTimeoutError::Disconnected) => {
+                    panic!("plugin exited during request {}", id)
+                }
+            };
+            // keep responses to other requests for their callers
+            if let Some(other) = msg["id"].as_u64() {
+                self.responses.insert(other, msg);
+            }
+        };
+        match msg.get("error") {
+            Some(error) => Err(error.clone()),
+            None => Ok(msg["result"].clone()),
+        }
+    }
+
+    /// Calls `method`, returning its result or error
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value, Value> {
+        let id = self.request(method, params);
+        self.response(id)
+    }
+
+    /// Polls `pruning-status` until `f` accepts it
+    pub fn wait_for_status<F: Fn(&Value) -> bool>(&mut self, f: F) -> Value {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let status = self.call("pruning-status", json!({})).unwrap();
+            if f(&status) {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "timed out at {}", status);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Waits for the pruning check run on startup to finish
+    pub fn wait_for_first_run(&mut self) -> Value {
+        self.wait_for_status(|s| !s["last_run"].is_null())
+    }
+
+    /// Closes stdin, as happens when lightningd exits, and waits for the plugin to follow
+    pub fn stop(self) -> ExitStatus {
+        let Plugin { child, stdin, .. } = self;
+        drop(stdin);
+        wait(child)
+    }
+
+    /// Waits for the plugin to exit by itself, e.g. on the `shutdown` notification
+    pub fn wait(self) -> ExitStatus {
+        wait(self.child)
+    }
+}
+
+fn wait(mut child: Child) -> ExitStatus {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("plugin did not exit");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,354 @@
+mod common;
+
+use serde_json::json;
+
+use common::{MockBitcoind, MockLightningd, Plugin};
+
+/// a long interval, so only the check on startup and `pruning-now` run
+fn options() -> serde_json::Value {
+    json!({
+        "pruning-interval": 3600,
+        "pruning-block-batch": 0,
+    })
+}
+
+#[test]
+fn manifest() {
+    let mut plugin = Plugin::spawn();
+    let manifest = plugin.call("getmanifest", json!({})).unwrap();
+    let methods: Vec<&str> = manifest["rpcmethods"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["name"].as_str().unwrap())
+        .collect();
+    for method in &["pruning-status", "pruning-now", "pruning-pause"] {
+        assert!(methods.contains(method), "{} missing", method);
+    }
+    assert!(manifest["options"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|o| o["name"] == "pruning-interval"));
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn prunes_to_rescan_depth() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("rescan", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    let status = plugin.wait_for_first_run();
+    // lightningd at 1000 may rescan 15 blocks
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+    assert_eq!(status["pruned_height"], 984);
+    assert_eq!(status["bitcoind_pruneheight"], 985);
+
+    // nothing new to prune until lightningd moves on
+    let report = plugin.call("pruning-now", json!({})).unwrap();
+    assert!(report["skipped"].is_string(), "{}", report);
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+
+    lightningd.state().blockheight = 1010;
+    bitcoind.state().blocks = 1010;
+    let report = plugin.call("pruning-now", json!({})).unwrap();
+    assert_eq!(report["prune_height"], 994);
+    assert_eq!(bitcoind.state().prune_calls, vec![984, 994]);
+
+    let history = plugin.call("pruning-history", json!({})).unwrap();
+    assert_eq!(history["total"], 2);
+    assert_eq!(history["history"][0]["prune_height"], 994);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn prunes_to_absolute_rescan_height() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("absolute-rescan", bitcoind.addr);
+    // lightningd may rescan from block 950
+    lightningd.state().rescan = -950;
+    let mut plugin = Plugin::start(&lightningd, options());
+    let status = plugin.wait_for_first_run();
+    assert_eq!(bitcoind.state().prune_calls, vec![949]);
+    assert_eq!(status["rescan"], -950);
+    assert!(plugin.stop().success());
+}
+
+#[cfg(feature = "backend")]
+#[test]
+fn backend_survives_pruning_setup_failure() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("setup-failure", bitcoind.addr);
+    lightningd.state().listconfigs_error = true;
+    let mut plugin = Plugin::start(&lightningd, options());
+    let err = plugin.call("pruning-now", json!({})).unwrap_err();
+    assert!(
+        err["data"].as_str().unwrap().contains("pruning disabled"),
+        "{}",
+        err
+    );
+    let status = plugin.call("pruning-status", json!({})).unwrap();
+    assert!(status["last_error"].is_string(), "{}", status);
+    let info = plugin.call("getchaininfo", json!({})).unwrap();
+    assert_eq!(info["blockcount"], 1000);
+    assert!(bitcoind.state().prune_calls.is_empty());
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn reads_cookie_lazily() {
+    let bitcoind = MockBitcoind::spawn();
+    bitcoind.state().authorization = Some("Basic X19jb29raWVfXzpmaXJzdA==".to_owned());
+    let lightningd = MockLightningd::spawn("cookie", bitcoind.addr);
+    let datadir = lightningd.dir.join("bitcoin");
+    std::fs::create_dir_all(datadir.join("regtest")).unwrap();
+    lightningd.state().bitcoin_datadir = Some(datadir.clone());
+    // bitcoind hasn't written its cookie yet
+    let mut plugin = Plugin::start(&lightningd, options());
+    let status = plugin.wait_for_first_run();
+    assert!(status["last_error"].is_string(), "{}", status);
+    assert!(bitcoind.state().prune_calls.is_empty());
+
+    let cookie = datadir.join("regtest").join(".cookie");
+    std::fs::write(&cookie, "__cookie__:first").unwrap();
+    plugin.call("pruning-now", json!({})).unwrap();
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+
+    // bitcoind restarted with a new cookie
+    bitcoind.state().authorization = Some("Basic X19jb29raWVfXzpzZWNvbmQ=".to_owned());
+    std::fs::write(&cookie, "__cookie__:second").unwrap();
+    lightningd.state().blockheight = 1010;
+    bitcoind.state().blocks = 1010;
+    plugin.call("pruning-now", json!({})).unwrap();
+    assert_eq!(bitcoind.state().prune_calls, vec![984, 994]);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn preserves_channel_funding() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("channel", bitcoind.addr);
+    lightningd.state().short_channel_ids = vec!["900x1x0".to_owned()];
+    let mut plugin = Plugin::start(&lightningd, options());
+    plugin.wait_for_first_run();
+    assert_eq!(bitcoind.state().prune_calls, vec![899]);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn defers_while_syncing() {
+    let bitcoind = MockBitcoind::spawn();
+    bitcoind.state().initialblockdownload = true;
+    let lightningd = MockLightningd::spawn("ibd", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    let status = plugin.wait_for_first_run();
+    assert_eq!(status["skipped"], "bitcoind is in initial block download");
+
+    // lightningd far behind bitcoind
+    bitcoind.state().initialblockdownload = false;
+    bitcoind.state().blocks = 2000;
+    let report = plugin.call("pruning-now", json!({})).unwrap();
+    assert!(report["anomaly"].is_string(), "{}", report);
+    assert!(bitcoind.state().prune_calls.is_empty());
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn refetch_asks_each_peer_once() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("refetch", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    plugin.wait_for_first_run();
+    assert_eq!(bitcoind.state().pruneheight, 985);
+
+    plugin
+        .call("pruning-refetch", json!({ "from": 983, "to": 986 }))
+        .unwrap();
+    let status = plugin.wait_for_status(|s| s["refetch"]["running"] == false);
+    assert!(status["refetch"]["error"].is_null(), "{}", status);
+    assert_eq!(status["refetch"]["fetched"], 2);
+    assert_eq!(status["refetch"]["available"], 2);
+    assert_eq!(bitcoind.state().fetch_requests, vec![(983, 7), (984, 7)]);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn nothing_prunable() {
+    let bitcoind = MockBitcoind::spawn();
+    bitcoind.state().unprunable = true;
+    let lightningd = MockLightningd::spawn("unprunable", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    let status = plugin.wait_for_first_run();
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+    assert_eq!(status["prune_height"], 984);
+    assert!(status["pruned_height"].is_null(), "{}", status);
+    assert!(status["last_prune"].is_null(), "{}", status);
+    assert!(status["last_error"].is_null(), "{}", status);
+    assert_eq!(status["bitcoind_pruneheight"], 0);
+
+    let report = plugin.call("pruning-now", json!({})).unwrap();
+    assert!(report["pruned_height"].is_null(), "{}", report);
+    let history = plugin.call("pruning-history", json!({})).unwrap();
+    assert_eq!(history["total"], 0);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn refetches_rescan_gap_on_startup() {
+    let bitcoind = MockBitcoind::spawn();
+    bitcoind.state().pruneheight = 960;
+    let lightningd = MockLightningd::spawn("rescan-gap", bitcoind.addr);
+    // lightningd was restarted to rescan from block 950, which has since been pruned
+    lightningd.state().rescan = -950;
+    let mut plugin = Plugin::start(&lightningd, options());
+    let status = plugin.wait_for_status(|s| s["refetch"]["running"] == false);
+    assert_eq!(status["refetch"]["from"], 950);
+    assert_eq!(status["refetch"]["to"], 959);
+    assert_eq!(status["refetch"]["fetched"], 10);
+    let fetched: Vec<u64> = bitcoind
+        .state()
+        .fetch_requests
+        .iter()
+        .map(|(height, _)| *height)
+        .collect();
+    assert_eq!(fetched, (950..960).collect::<Vec<_>>());
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn dry_run() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("dry-run", bitcoind.addr);
+    let mut options = options();
+    options["pruning-dry-run"] = true.into();
+    let mut plugin = Plugin::start(&lightningd, options);
+    let status = plugin.wait_for_first_run();
+    assert_eq!(status["prune_height"], 984);
+    assert!(bitcoind.state().prune_calls.is_empty());
+
+    // overridden for a single call
+    let report = plugin.call("pruning-now", json!([false])).unwrap();
+    assert_eq!(report["pruned_height"], 984);
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn pause_survives_restart() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("pause", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    plugin.wait_for_first_run();
+    let report = plugin.call("pruning-pause", json!([])).unwrap();
+    assert!(report["paused"].is_object(), "{}", report);
+    assert!(plugin.stop().success());
+
+    lightningd.state().blockheight = 1010;
+    bitcoind.state().blocks = 1010;
+    let mut plugin = Plugin::start(&lightningd, options());
+    let status = plugin.wait_for_status(|s| s["paused"].is_object());
+    assert!(status["last_run"].is_null(), "{}", status);
+    assert!(plugin.call("pruning-now", json!({})).is_err());
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+
+    plugin.call("pruning-resume", json!({})).unwrap();
+    // the check on startup may only run now, in which case this one has nothing left to do
+    plugin.call("pruning-now", json!({})).unwrap();
+    assert_eq!(bitcoind.state().prune_calls, vec![984, 994]);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn status_during_prune() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("status-during-prune", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    plugin.wait_for_first_run();
+    lightningd.state().blockheight = 1010;
+    bitcoind.state().blocks = 1010;
+    bitcoind.state().stall_prune = true;
+    let prune = plugin.request("pruning-now", json!({}));
+    // answered while pruneblockchain is still outstanding
+    plugin.call("pruning-status", json!({})).unwrap();
+    bitcoind.state().stall_prune = false;
+    let report = plugin.response(prune).unwrap();
+    assert_eq!(report["pruned_height"], 994);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn shutdown_during_prune() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("shutdown-during-prune", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    plugin.wait_for_first_run();
+    lightningd.state().blockheight = 1010;
+    bitcoind.state().blocks = 1010;
+    bitcoind.state().stall_prune = true;
+    plugin.request("pruning-now", json!({}));
+    plugin.notify("shutdown", json!({}));
+    assert!(plugin.wait().success());
+}
+
+#[test]
+fn setconfig() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("setconfig", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    plugin.wait_for_first_run();
+    plugin
+        .call(
+            "setconfig",
+            json!({ "config": "pruning-dry-run", "val": true }),
+        )
+        .unwrap();
+    let error = plugin
+        .call(
+            "setconfig",
+            json!({ "config": "pruning-interval", "val": 0 }),
+        )
+        .unwrap_err();
+    assert_eq!(error["code"], 8);
+    plugin.wait_for_status(|s| s["dry_run"] == true);
+
+    lightningd.state().blockheight = 1010;
+    bitcoind.state().blocks = 1010;
+    let report = plugin.call("pruning-now", json!({})).unwrap();
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn block_batch() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("block-batch", bitcoind.addr);
+    let mut options = options();
+    options["pruning-block-batch"] = 2.into();
+    let mut plugin = Plugin::start(&lightningd, options);
+    let last_run = plugin.wait_for_first_run()["last_run"].clone();
+    lightningd.state().blockheight = 1010;
+    bitcoind.state().blocks = 1010;
+    plugin.notify("block_added", json!({ "block_added": { "height": 1009 } }));
+    // one block short of a batch: `pruning-history` is queued to the pruning loop behind the
+    // block, and checks run in the loop, so once it answers any check would have finished
+    let history = plugin.call("pruning-history", json!({})).unwrap();
+    assert_eq!(history["total"], 1);
+    let status = plugin.call("pruning-status", json!({})).unwrap();
+    assert_eq!(status["last_run"], last_run);
+    assert_eq!(bitcoind.state().prune_calls, vec![984]);
+    plugin.notify("block_added", json!({ "block_added": { "height": 1010 } }));
+    plugin.wait_for_status(|s| s["pruned_height"] == 994);
+    assert_eq!(bitcoind.state().prune_calls, vec![984, 994]);
+    assert!(plugin.stop().success());
+}
+
+#[test]
+fn shutdown_notification() {
+    let bitcoind = MockBitcoind::spawn();
+    let lightningd = MockLightningd::spawn("shutdown", bitcoind.addr);
+    let mut plugin = Plugin::start(&lightningd, options());
+    plugin.wait_for_first_run();
+    plugin.notify("shutdown", json!({}));
+    assert!(plugin.wait().success());
+}